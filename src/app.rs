@@ -1,15 +1,23 @@
-use color_eyre::{Result, eyre::Report};
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::mpsc};
+use chrono::Local;
+use color_eyre::{Result, eyre::{eyre, Report}};
 use crate::menus::{
-    cluster_menu::ClusterMenu,
+    cluster_menu::{ClusterMenu, CLUSTER_FILE},
     spawner_menu::SpawnerMenu,
-    cluster::SessionType};
+    cluster::{Cluster, SessionType, is_alive, read_remote_environment},
+    connection_error::ConnectionError};
+use crate::menus::{job::Job, spawner::{SpawnResult, Spawner}};
+use crate::menus::bundle::{Bundle, Conflict};
+use crate::paths::expand_local;
+use crate::double_column_menu::counter::Counter;
 use crate::double_column_menu::double_column_menu::DoubleColumnMenu;
-use crate::double_column_menu::render_helper_functions::render_info_dialog;
-use ratatui::{backend::CrosstermBackend, Terminal};
-use ratatui::prelude::*;
-use crate::tui::Tui;
+use crate::event::Event;
+use crate::progress::{Progress, Stage, TaskEvent, spawn_task};
+use crate::run_log::RunLog;
 use ssh2::Session;
-use rpassword;
+
+/// Frames of the spinner that shows that a task is running.
+const SPINNER: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
 
 
 #[derive(Debug, Default)]
@@ -20,6 +28,19 @@ pub enum Action {
     OpenClusterMenu,
     OpenSpawnerMenu,
     StartSpawner,
+    OpenJobList,
+    AttachJob,
+    OpenLog,
+    SubmitSecret,
+    CheckMaster,
+    ExitMaster,
+    OpenExport,
+    OpenImport,
+    SubmitPath,
+    /// The answer for the first open conflict of the import.
+    AnswerConflict(Conflict),
+    /// A failure in a menu, shown in the error popup.
+    ShowError(String),
 }
 
 #[derive(Debug, Default)]
@@ -34,6 +55,132 @@ pub enum Popup {
     #[default]
     None,
     Error(String),
+    Info(String),
+    JobSelect(Vec<Job>, Counter),
+    /// The log of the last run.
+    Log,
+    Secret(SecretInput),
+    /// The file to export a bundle to or to import it from.
+    Path(PathInput),
+    /// An import that waits for the answers to its conflicts.
+    ImportConflict(PendingImport),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathPurpose {
+    Export,
+    Import,
+}
+
+/// Input for the path of a bundle file.
+#[derive(Debug)]
+pub struct PathInput {
+    pub prompt: String,
+    pub value: String,
+    pub purpose: PathPurpose,
+    /// Remove the user and identity file from the export.
+    pub strip_user: bool,
+    /// Set once the user is asked to confirm overwriting the file.
+    pub overwrite: bool,
+}
+
+impl PathInput {
+    fn new(prompt: String, value: String, purpose: PathPurpose) -> Self {
+        Self { prompt, value, purpose, strip_user: false, overwrite: false }
+    }
+
+    /// The text of the input dialog.
+    pub fn format_dialog(&self) -> String {
+        let hint = match self.overwrite {
+            true => "The file exists. Press `Enter` to overwrite it, `Esc` to cancel.",
+            false => "Press `Enter` to confirm, `Esc` to cancel.",
+        };
+        match self.purpose {
+            PathPurpose::Export => {
+                let user = match self.strip_user {
+                    true => "User and identity file: removed (`Tab` to keep)",
+                    false => "User and identity file: kept (`Tab` to remove)",
+                };
+                format!("{}\n{}\n{}\n{}", self.prompt, self.value, user, hint)
+            },
+            PathPurpose::Import => format!("{}\n{}\n{}", self.prompt, self.value, hint),
+        }
+    }
+}
+
+/// A bundle that is imported once it is known what to do with the
+/// clusters that already exist.
+#[derive(Debug)]
+pub struct PendingImport {
+    pub bundle: Bundle,
+    /// The names of the existing clusters that are not answered yet.
+    pub conflicts: Vec<String>,
+    pub answers: HashMap<String, Conflict>,
+}
+
+impl PendingImport {
+    /// The text of the dialog for the first open conflict. `new_name` is
+    /// the name that the cluster gets if it is renamed.
+    pub fn format_dialog(&self, new_name: &str) -> String {
+        let name = self.conflicts.first().map(|n| n.as_str()).unwrap_or_default();
+        format!("Cluster {} already exists.\n\
+                 `r`: add it as {}, `m`: merge its sessions, `s`: skip it\n\
+                 Press `Esc` to cancel the import.", name, new_name)
+    }
+}
+
+/// Masked input for a password, a passphrase or a one-time code.
+#[derive(Debug, Default)]
+pub struct SecretInput {
+    pub prompt: String,
+    pub value: String,
+    /// Why the previous attempt failed.
+    pub error: Option<String>,
+    /// The authentication that the input is for.
+    pub session_type: SessionType,
+}
+
+impl SecretInput {
+    pub fn new(session_type: SessionType, error: Option<String>) -> SecretInput {
+        let prompt = match session_type {
+            SessionType::Passphrase => "Enter the passphrase of your private key:",
+            _ => "Enter your password:",
+        };
+        SecretInput {
+            prompt: prompt.to_string(),
+            value: String::new(),
+            error,
+            session_type,
+        }
+    }
+
+    /// The text of the input dialog, with the input masked.
+    pub fn format_dialog(&self) -> String {
+        let hint = self.error.as_deref()
+            .unwrap_or("Press `Enter` to connect, `Esc` to cancel.");
+        format!("{}\n{}\n{}", self.prompt, "*".repeat(self.value.chars().count()), hint)
+    }
+}
+
+/// A connection or spawn that runs on a worker thread.
+#[derive(Debug)]
+pub struct Task {
+    pub title: String,
+    pub stage: Option<Stage>,
+    pub spinner: usize,
+    progress: Progress,
+}
+
+impl Task {
+    /// The title of the log pane while the task runs.
+    pub fn format_status(&self) -> String {
+        let mut status = format!(" {} {}", SPINNER[self.spinner % SPINNER.len()], self.title);
+        if let Some(stage) = self.stage {
+            status.push_str(&format!(": {} ...", stage));
+        }
+        status.push(' ');
+        status
+    }
 }
 
 #[derive(Default)]
@@ -46,13 +193,29 @@ pub struct App {
     pub menu: Menu,
     pub popup: Popup,
     pub session: Option<Session>,
+    /// The running background task, at most one at a time.
+    pub task: Option<Task>,
+    /// Log of the last connection or spawn.
+    pub log: RunLog,
+    /// Channel to the event loop, used by the background tasks.
+    pub sender: Option<mpsc::Sender<Event>>,
+    /// How the session was authenticated, to reconnect the same way.
+    pub session_type: SessionType,
+    /// The action that is repeated once the lost session is reconnected.
+    pub resume: Option<Action>,
+    last_task_id: u64,
 }
 
 impl App {
     pub fn new() -> Result<Self> {
-        let mut new_app = Self::default();
-        new_app.should_redraw = false;
+        let mut new_app = Self {
+            should_redraw: false,
+            ..Default::default()
+        };
         new_app.cluster_menu.load_entries()?;
+        Cluster::move_legacy_presets(new_app.cluster_menu.get_entries())?;
+        new_app.show_migration_message(
+            new_app.cluster_menu.get_entries().migration_message(CLUSTER_FILE));
         Ok(new_app)
     }
 
@@ -64,137 +227,560 @@ impl App {
         self.menu = Menu::Cluster;
     }
 
-    pub fn open_spawner_menu(&mut self) {
-        let cluster = self.cluster_menu.get_entry().unwrap();
-        // check if the identity file is set
-        // if not, open the spawner menu with password
-        let session_type = if cluster.identity_file.is_empty() {
-            SessionType::Password
-        } else {
-            SessionType::IdentityFile
-        };
-        self.open_session(session_type);
+    pub fn open_spawner_menu(&mut self) -> Result<()> {
+        let cluster = self.cluster_menu.get_entry()?;
+        let session_type = cluster.session_type();
+        self.resume = None;
+        // report invalid fields before asking for the password
+        cluster.check(&session_type)?;
+        self.open_session(session_type)
     }
 
-    pub fn set_session(&mut self, session: Session) {
-        let cluster = self.cluster_menu.get_entry().unwrap();
-        cluster.add_cluster_to_ssh_config().unwrap();
-        self.spawner_menu.cluster_name = cluster.name.clone();
-        self.spawner_menu.load_entries().unwrap();
+    pub fn set_session(&mut self, session: Session) -> Result<()> {
+        let cluster = self.cluster_menu.get_entry()?;
+        cluster.add_cluster_to_ssh_config()?;
+        let certificate_warning = cluster.certificate_warning();
+        self.spawner_menu.preset_file = cluster.preset_file();
+        // collect the messages, such that none of them hides another one
+        let mut errors = Vec::new();
+        self.spawner_menu.shared = Spawner::load_shared(&cluster.shared_presets)
+            .unwrap_or_else(|e| {
+                errors.push(e.to_string());
+                Vec::new()
+            });
+        self.spawner_menu.load_entries()?;
+        // without the environment the paths are shown as they are
+        self.spawner_menu.environment = read_remote_environment(&session)
+            .unwrap_or_default();
+        let mut messages: Vec<String> = self.spawner_menu.get_entries()
+            .migration_message(&self.spawner_menu.preset_file).into_iter().collect();
+        messages.extend(certificate_warning);
+        self.show_messages(errors, messages);
         self.menu = Menu::Spawner;
         self.session = Some(session);
+        Ok(())
     }
 
+    /// Show all errors and messages in one popup. It is an error popup
+    /// if there is any error.
+    fn show_messages(&mut self, errors: Vec<String>, messages: Vec<String>) {
+        let is_error = !errors.is_empty();
+        let text = errors.into_iter().chain(messages).collect::<Vec<String>>().join("\n");
+        if is_error {
+            self.popup = Popup::Error(text);
+        } else if !text.is_empty() {
+            self.popup = Popup::Info(text);
+        }
+    }
+
+    /// Tell the user that a configuration file was upgraded.
+    fn show_migration_message(&mut self, message: Option<String>) {
+        if let Some(message) = message {
+            self.popup = Popup::Info(message);
+        }
+    }
+
+    /// Format an error for the error popup, with a hint on what to do
+    /// for connection errors.
     pub fn format_error_message(&self, error: &Report) -> String {
-        let mut error_msg = format!("Error: {}", error);
-        if error_msg.contains("failed to lookup address information") {
-            error_msg = "Error: failed to lookup address information: \
-                         Name or service not known.\n\
-                         Please check the hostname!"
-                         .to_string();
-        }
-        if error_msg.contains("Connection refused") {
-            error_msg = "Error: Connection refused.\n\
-                         Please check if the SSH port is open!"
-                         .to_string();
-        }
-        if error_msg.contains("Username/PublicKey") {
-            error_msg = "Error: Username/PublicKey combination invalid.\n\
-                         Please check both entries!"
-                         .to_string();
-        }
-        if error_msg.contains("File not found") {
-            error_msg = "Error: Identity file not found.\n\
-                         Please check if the file path exists!"
-                         .to_string();
-        }
-        error_msg
-    }
-
-    pub fn open_session(&mut self, session_type: SessionType) {
-        // get password or passphrase from the user
-        let password = match session_type {
-            SessionType::Passphrase => {
-                ask_for_passphrase("Enter your passphrase: ").unwrap()
+        match error.downcast_ref::<ConnectionError>() {
+            Some(e) => format!("Error: {}\n{}", e, e.remediation()),
+            None => format!("Error: {}", error),
+        }
+    }
+
+    /// Connect to the selected cluster. Passwords and passphrases are
+    /// asked for in a popup first.
+    pub fn open_session(&mut self, session_type: SessionType) -> Result<()> {
+        match session_type {
+            SessionType::IdentityFile | SessionType::Agent => {
+                self.connect(session_type, String::new())?
             },
-            SessionType::Password => {
-                ask_for_passphrase("Enter your password: ").unwrap()
+            _ => self.popup = Popup::Secret(SecretInput::new(session_type, None)),
+        }
+        Ok(())
+    }
+
+    /// Connect with the password or passphrase of the input popup.
+    pub fn submit_secret(&mut self) -> Result<()> {
+        if let Popup::Secret(input) = std::mem::take(&mut self.popup) {
+            self.connect(input.session_type, input.value)?;
+        }
+        Ok(())
+    }
+
+    fn connect(&mut self, session_type: SessionType, password: String) -> Result<()> {
+        let cluster = self.cluster_menu.get_entry()?.clone();
+        let progress = self.start_task(format!("Connecting to {}", cluster.name));
+        spawn_task(progress,
+                   move |progress| cluster.create_session(&session_type, &password, progress),
+                   move |result| TaskEvent::Connected(session_type, result))
+    }
+
+    /// Handle the result of the connection task.
+    fn on_connected(&mut self, session_type: SessionType, result: Result<Session>) {
+        let error = match result.and_then(|session| self.use_session(session)) {
+            Ok(()) => {
+                self.session_type = session_type;
+                if let Some(action) = self.resume.take() {
+                    self.action = action;
+                    self.handle_action();
+                }
+                return;
             },
-            _ => "".to_string(),
+            Err(e) => e,
         };
-        // render a info dialog that says "Connecting To Cluster ..."
-        self.render_connect_screen();
-        let cluster = self.cluster_menu.get_entry().unwrap();
-        let session_result = cluster.create_session(
-            &session_type, &password);
-        // handle the result
-        match session_result {
-            Ok(session) => {
-                self.set_session(session);
+        match (session_type, error.downcast_ref::<ConnectionError>()) {
+            // the private key is encrypted, ask for the passphrase
+            (SessionType::IdentityFile, Some(ConnectionError::PrivateKey(_))) => {
+                self.popup = Popup::Secret(SecretInput::new(SessionType::Passphrase, None));
             },
-            Err(e) => {
-                let error_msg = self.format_error_message(&Report::msg(e));
-                // check if the error message contains keyfile auth failed
-                // if so, open the session but with a passphrase
-                if error_msg.contains("keyfile auth failed") {
-                    if session_type == SessionType::IdentityFile {
-                        self.open_session(SessionType::Passphrase);
-                        return;
-                    }
-                }
-                self.popup = Popup::Error(error_msg);
+            // the password or passphrase was probably mistyped, ask again
+            (SessionType::Password | SessionType::Passphrase, Some(e))
+                if e.is_rejected_secret() => {
+                let retry = format!("{}. Please try again.", e);
+                self.popup = Popup::Secret(SecretInput::new(session_type, Some(retry)));
+            },
+            _ => {
+                self.resume = None;
+                self.popup = Popup::Error(self.format_error_message(&error));
+            },
+        }
+    }
+
+    /// Use a new session. After a reconnect it replaces the lost session,
+    /// otherwise the spawner menu of the cluster is opened.
+    fn use_session(&mut self, session: Session) -> Result<()> {
+        if self.resume.is_some() {
+            self.session = Some(session);
+            return Ok(());
+        }
+        self.set_session(session)
+    }
+
+    /// Make sure that the session still works before scheduler commands
+    /// run on it. A lost session is reconnected with the same
+    /// authentication, passwords are asked for again, and the action is
+    /// repeated once connected. Returns whether the session can be used.
+    fn check_session(&mut self, action: Action) -> Result<bool> {
+        let name = self.cluster_menu.get_entry()?.name.clone();
+        let session = self.session.as_ref()
+            .ok_or_else(|| eyre!("Not connected to {}", name))?;
+        if is_alive(session) {
+            return Ok(true);
+        }
+        self.session = None;
+        self.resume = Some(action);
+        match self.session_type {
+            SessionType::Password | SessionType::Passphrase => {
+                let reason = format!("The connection to {} was lost. Please log in again.", name);
+                self.popup = Popup::Secret(SecretInput::new(self.session_type, Some(reason)));
             },
+            session_type => self.connect(session_type, String::new())?,
+        }
+        Ok(false)
+    }
+
+
+    pub fn start_spawner(&mut self) -> Result<()> {
+        if self.spawner_menu.is_new_entry() {
+            return Ok(());
+        }
+        let spawner = self.spawner_menu.get_entry()?
+            .resolve(self.spawner_menu.get_entries())?;
+        spawner.check()?;
+        if !self.check_session(Action::StartSpawner)? {
+            return Ok(());
+        }
+        let cluster = self.cluster_menu.get_entry()?.clone();
+        let mut session = self.session.clone()
+            .ok_or_else(|| eyre!("Not connected to {}", cluster.name))?;
+        let progress = self.start_task(format!("Spawning {}", spawner.preset_name));
+        spawn_task(progress,
+                   move |progress| spawner.spawn(&mut session, &cluster, progress),
+                   TaskEvent::Spawned)
+    }
+
+    /// Handle the result of the spawn task. The application quits once
+    /// the editor is launched.
+    fn on_spawned(&mut self, result: Result<SpawnResult>) {
+        match result.and_then(|result| self.record_job(&result.job.id)) {
+            Ok(()) => self.quit(),
+            Err(e) => self.popup = Popup::Error(
+                format!("{}\nPress `o` to show the log.", self.format_error_message(&e))),
+        }
+    }
+
+    /// Remember the job of the selected spawner such that later actions
+    /// target exactly this job.
+    pub fn record_job(&mut self, job_id: &str) -> Result<()> {
+        let spawner = self.spawner_menu.get_entry_mut()?;
+        spawner.job_id = job_id.to_string();
+        let preset_name = spawner.preset_name.clone();
+        let filename = self.spawner_menu.get_filename().to_string();
+        Spawner::record_job(&filename, &preset_name, job_id)
+    }
+
+    // =======================================================================
+    //             BACKGROUND TASKS
+    // =======================================================================
+
+    /// Register a new background task and return its progress.
+    fn start_task(&mut self, title: String) -> Progress {
+        self.last_task_id += 1;
+        let progress = Progress::new(self.last_task_id, self.sender.clone());
+        self.log.start(&title);
+        self.task = Some(Task {
+            title,
+            stage: None,
+            spinner: 0,
+            progress: progress.clone(),
+        });
+        progress
+    }
+
+    /// Abort the running task. The worker stops at the next stage or
+    /// while waiting for a remote command, and its result is ignored.
+    pub fn abort_task(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.progress.abort();
+            self.resume = None;
+            self.popup = Popup::Info(format!("{}: aborted.", task.title));
+            self.finish_log("Aborted");
+        }
+    }
+
+    /// Close the log of the run and keep it for troubleshooting.
+    fn finish_log(&mut self, line: &str) {
+        self.log.push_line(line);
+        if let Err(e) = self.log.save() {
+            self.popup = Popup::Error(format!("Error: could not save the log: {}", e));
+        }
+    }
+
+    pub fn open_log(&mut self) {
+        self.popup = match self.log.is_empty() {
+            true => Popup::Info("Nothing has run yet.".to_string()),
+            false => Popup::Log,
         };
     }
 
+    /// Advance the spinner of the running task.
+    pub fn tick(&mut self) {
+        if let Some(task) = &mut self.task {
+            task.spinner = task.spinner.wrapping_add(1);
+        }
+        // keep the idle session open; libssh2 only sends a message when
+        // the interval passed. Tasks use the session themselves, and a
+        // dead session is noticed before the next scheduler command.
+        if let (None, Some(session)) = (&self.task, &self.session) {
+            let _ = session.keepalive_send();
+        }
+    }
 
-    pub fn start_spawner(&mut self) {
-        if self.cluster_menu.is_new_entry() {
+    /// Handle an event of a background task. Events of aborted tasks
+    /// are dropped.
+    pub fn handle_task_event(&mut self, id: u64, event: TaskEvent) {
+        let Some(task) = self.task.as_mut().filter(|t| t.progress.id() == id) else {
             return;
+        };
+        match event {
+            TaskEvent::Stage(stage) => {
+                task.stage = Some(stage);
+                self.log.push_line(&format!("{} ...", stage));
+            },
+            TaskEvent::Output(text) => self.log.push_output(&text),
+            TaskEvent::Connected(session_type, result) => {
+                self.task = None;
+                self.finish_log(&match &result {
+                    Ok(_) => "Connected".to_string(),
+                    Err(e) => format!("Error: {}", e),
+                });
+                self.on_connected(session_type, result);
+            },
+            TaskEvent::Spawned(result) => {
+                self.task = None;
+                self.finish_log(&match &result {
+                    Ok(result) => format!("Launched the editor on job {} ({})",
+                                          result.job.id, result.job.node),
+                    Err(e) => format!("Error: {}", e),
+                });
+                self.on_spawned(result);
+            },
         }
-        self.quit();
-        Tui::reset().expect("failed to reset the terminal");
-        let cluster = self.cluster_menu.get_entry().unwrap();
-        let spawner = self.spawner_menu.get_entry().unwrap();
-        spawner.spawn(self.session.as_mut().unwrap(), &cluster).unwrap();
     }
 
-    pub fn handle_action(&mut self) {
-        match self.action {
-            Action::Quit => { self.quit(); }
-            Action::OpenClusterMenu => { self.open_cluster_menu(); }
-            Action::OpenSpawnerMenu => { self.open_spawner_menu(); }
-            Action::StartSpawner => { self.start_spawner(); }
-            _ => {}
+    /// List the running jobs of the user to attach the spawner to one.
+    pub fn open_job_list(&mut self) -> Result<()> {
+        if !self.check_session(Action::OpenJobList)? {
+            return Ok(());
+        }
+        let cluster = self.cluster_menu.get_entry()?;
+        let session = self.session.as_ref()
+            .ok_or_else(|| eyre!("Not connected to {}", cluster.name))?;
+        let jobs = Job::list_running(session, cluster)?;
+        self.popup = match jobs.is_empty() {
+            true => Popup::Error("No running jobs found.".to_string()),
+            false => {
+                let counter = Counter::new(jobs.len() as u32);
+                Popup::JobSelect(jobs, counter)
+            },
         };
-        self.action = Action::None;
+        Ok(())
     }
 
-    pub fn render_connect_screen(&mut self) {
-        // render a info dialog that says "Connecting To Cluster ..."
-        let backend = CrosstermBackend::new(std::io::stderr());
-        let mut terminal = Terminal::new(backend).unwrap();
-        terminal.draw(|frame| {
-            render_info_dialog(
-                frame, "Connecting To Cluster ...", Color::Yellow, 1);
-        }).unwrap();
-        terminal.hide_cursor().unwrap();
-        self.should_redraw = true;
+    /// Attach the selected spawner to the job selected in the job list.
+    pub fn attach_job(&mut self) -> Result<()> {
+        let popup = std::mem::take(&mut self.popup);
+        if let Popup::JobSelect(jobs, counter) = popup {
+            let job = jobs.get(counter.get_value() as usize)
+                .ok_or_else(|| eyre!("No job selected"))?;
+            self.spawner_menu.get_entry_mut()?.job_id = job.id.clone();
+            self.start_spawner()?;
+        }
+        Ok(())
     }
 
-}
+    /// Check whether the master connection of the selected cluster runs.
+    pub fn check_master(&mut self) -> Result<()> {
+        let cluster = self.cluster_menu.get_entry()?;
+        let running = cluster.check_master()?;
+        let id = cluster.id.clone();
+        self.cluster_menu.master_status.insert(id, (running, Local::now()));
+        Ok(())
+    }
+
+    /// Close the master connection of the selected cluster.
+    pub fn exit_master(&mut self) -> Result<()> {
+        let cluster = self.cluster_menu.get_entry()?;
+        cluster.exit_master()?;
+        let message = format!("Closed the master connection of {}.", cluster.name);
+        let id = cluster.id.clone();
+        self.cluster_menu.master_status.insert(id, (false, Local::now()));
+        self.popup = Popup::Info(message);
+        Ok(())
+    }
+
+    // =======================================================================
+    //             BUNDLES
+    // =======================================================================
+
+    /// Ask for the file to export the selected cluster to.
+    pub fn open_export(&mut self) -> Result<()> {
+        let cluster = self.cluster_menu.get_entry()?;
+        self.popup = Popup::Path(PathInput::new(
+            format!("Export {} and its sessions to:", cluster.name),
+            format!("{}.toml", cluster.name),
+            PathPurpose::Export));
+        Ok(())
+    }
+
+    /// Ask for the bundle file to import.
+    pub fn open_import(&mut self) {
+        self.popup = Popup::Path(PathInput::new(
+            "Import the bundle file:".to_string(), String::new(), PathPurpose::Import));
+    }
+
+    pub fn submit_path(&mut self) -> Result<()> {
+        if let Popup::Path(mut input) = std::mem::take(&mut self.popup) {
+            let file = PathBuf::from(expand_local(input.value.trim()));
+            match input.purpose {
+                PathPurpose::Export if file.exists() && !input.overwrite => {
+                    // ask again before the file is replaced
+                    input.overwrite = true;
+                    self.popup = Popup::Path(input);
+                },
+                PathPurpose::Export => self.export_bundle(&file, &input)?,
+                PathPurpose::Import => self.read_bundle(&file)?,
+            }
+        }
+        Ok(())
+    }
 
+    fn export_bundle(&mut self, file: &Path, input: &PathInput) -> Result<()> {
+        let cluster = self.cluster_menu.get_entry()?.clone();
+        let message = format!("Exported {} to {}.", cluster.name, file.display());
+        Bundle::create(vec![cluster], input.strip_user)?.write(file, input.overwrite)?;
+        self.popup = Popup::Info(message);
+        Ok(())
+    }
 
-pub fn ask_for_passphrase(message: &str) -> Result<String> {
-    // disable the alternative Terminal UI for now
-    Tui::reset().expect("failed to reset the terminal");
+    /// Read the bundle and ask what to do with the clusters that exist.
+    fn read_bundle(&mut self, file: &Path) -> Result<()> {
+        let bundle = Bundle::read(file)?;
+        // compare with the latest version of the file
+        self.cluster_menu.load_entries()?;
+        let names: Vec<String> = self.cluster_menu.get_entries().entry.iter()
+            .map(|c| c.name.clone()).collect();
+        let conflicts = bundle.conflicts(&names);
+        self.popup = Popup::ImportConflict(PendingImport {
+            bundle,
+            conflicts,
+            answers: HashMap::new(),
+        });
+        self.import_bundle()
+    }
 
-    // Prompt the user for a password without showing the input
-    let password = rpassword::prompt_password_stdout(message).unwrap();
-    Tui::enter_alternate_screen().unwrap();
+    /// Answer the first open conflict of the import.
+    pub fn answer_conflict(&mut self, conflict: Conflict) -> Result<()> {
+        if let Popup::ImportConflict(pending) = &mut self.popup {
+            if !pending.conflicts.is_empty() {
+                let name = pending.conflicts.remove(0);
+                pending.answers.insert(name, conflict);
+            }
+        }
+        self.import_bundle()
+    }
+
+    /// Import the bundle once all conflicts are answered.
+    fn import_bundle(&mut self) -> Result<()> {
+        match &self.popup {
+            Popup::ImportConflict(pending) if pending.conflicts.is_empty() => {},
+            _ => return Ok(()),
+        }
+        let Popup::ImportConflict(pending) = std::mem::take(&mut self.popup) else {
+            return Ok(());
+        };
+        let answers = pending.answers;
+        // clusters that were added meanwhile are renamed
+        let imported = pending.bundle.import(
+            |name| Ok(answers.get(name).copied().unwrap_or(Conflict::Rename)))?;
+        self.cluster_menu.load_entries()?;
+        let lines: Vec<String> = imported.iter().map(|c| c.format_line()).collect();
+        self.popup = Popup::Info(format!("Imported {}.", lines.join(", ")));
+        Ok(())
+    }
 
-    // Use the password as needed
-    Ok(password)
+    /// Perform the action of the last input. Failures are shown in the
+    /// error popup.
+    pub fn handle_action(&mut self) {
+        let result = match std::mem::take(&mut self.action) {
+            Action::Quit => { self.quit(); Ok(()) }
+            Action::OpenClusterMenu => { self.open_cluster_menu(); Ok(()) }
+            Action::OpenSpawnerMenu => self.open_spawner_menu(),
+            Action::StartSpawner => self.start_spawner(),
+            Action::OpenJobList => self.open_job_list(),
+            Action::AttachJob => self.attach_job(),
+            Action::OpenLog => { self.open_log(); Ok(()) }
+            Action::SubmitSecret => self.submit_secret(),
+            Action::CheckMaster => self.check_master(),
+            Action::ExitMaster => self.exit_master(),
+            Action::OpenExport => self.open_export(),
+            Action::OpenImport => { self.open_import(); Ok(()) }
+            Action::SubmitPath => self.submit_path(),
+            Action::AnswerConflict(conflict) => self.answer_conflict(conflict),
+            Action::ShowError(message) => Err(eyre!(message)),
+            Action::None => Ok(()),
+        };
+        if let Err(e) = result {
+            self.popup = Popup::Error(self.format_error_message(&e));
+        }
+    }
 
 }
 
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An app with a cluster and a preset, but without a connection.
+    fn create_app() -> App {
+        let mut app = App::default();
+        app.cluster_menu.add_entry(Cluster::new("levante", "levante.dkrz.de", "u", ""));
+        app.spawner_menu.add_entry(Spawner::new("cpu", "", "compute", "", "", ""));
+        app
+    }
+
+    /// Perform the action and return the message of the error popup.
+    fn error_of(app: &mut App, action: Action) -> String {
+        app.action = action;
+        app.handle_action();
+        match &app.popup {
+            Popup::Error(message) => message.clone(),
+            _ => panic!("expected an error popup"),
+        }
+    }
+
+    #[test]
+    fn test_actions_without_session() {
+        let mut app = create_app();
+        assert_eq!(error_of(&mut app, Action::StartSpawner), "Error: Not connected to levante");
+        assert_eq!(error_of(&mut app, Action::OpenJobList), "Error: Not connected to levante");
+        assert!(app.task.is_none());
+    }
+
+    #[test]
+    fn test_actions_without_entries() {
+        let mut app = App::default();
+        assert!(error_of(&mut app, Action::OpenSpawnerMenu).starts_with("Error: "));
+        app.popup = Popup::JobSelect(Vec::new(), Counter::new(0));
+        assert_eq!(error_of(&mut app, Action::AttachJob), "Error: No job selected");
+    }
+
+    #[test]
+    fn test_master_without_multiplexing() {
+        let mut app = create_app();
+        assert_eq!(error_of(&mut app, Action::CheckMaster),
+                   "Error: Multiplexing is disabled for levante");
+        assert!(app.cluster_menu.master_status.is_empty());
+    }
+
+    #[test]
+    fn test_lost_session() {
+        let mut app = create_app();
+        // a session without a connection counts as lost
+        app.session = Some(Session::new().unwrap());
+        app.session_type = SessionType::Password;
+        app.action = Action::OpenJobList;
+        app.handle_action();
+        assert!(app.session.is_none());
+        assert!(matches!(app.resume, Some(Action::OpenJobList)));
+        match &app.popup {
+            Popup::Secret(input) => {
+                assert_eq!(input.session_type, SessionType::Password);
+                assert!(input.error.as_ref().unwrap().contains("connection to levante was lost"));
+            },
+            _ => panic!("expected the password popup"),
+        }
+        // connecting from the cluster menu drops the pending action
+        app.popup = Popup::None;
+        app.action = Action::OpenSpawnerMenu;
+        app.handle_action();
+        assert!(app.resume.is_none());
+    }
+
+    #[test]
+    fn test_menu_error() {
+        let mut app = create_app();
+        assert_eq!(error_of(&mut app, Action::ShowError("disk full".to_string())),
+                   "Error: disk full");
+        assert!(matches!(app.action, Action::None));
+    }
+
+    #[test]
+    fn test_connection_error_message() {
+        let app = App::default();
+        let error = eyre!(ConnectionError::Scheduler {
+            command: "salloc".to_string(),
+            message: "invalid account".to_string(),
+        });
+        let message = app.format_error_message(&error);
+        assert!(message.starts_with("Error: salloc failed: invalid account\n"));
+        assert!(message.contains("account, partition, time"));
+    }
+
+    #[test]
+    fn test_show_messages() {
+        let mut app = App::default();
+        app.show_messages(Vec::new(), Vec::new());
+        assert!(matches!(app.popup, Popup::None));
+        app.show_messages(Vec::new(), vec!["upgraded".to_string(), "expires".to_string()]);
+        assert!(matches!(&app.popup, Popup::Info(text) if text == "upgraded\nexpires"));
+        app.show_messages(vec!["no shared presets".to_string()], vec!["expires".to_string()]);
+        assert!(matches!(&app.popup, Popup::Error(text) if text == "no shared presets\nexpires"));
+    }
+}
@@ -0,0 +1,426 @@
+pub mod output;
+pub mod completions;
+
+use std::{
+    io::{BufRead, IsTerminal, Write},
+    path::{Path, PathBuf}};
+use clap::{Parser, Subcommand, ValueEnum};
+use color_eyre::eyre::{eyre, Result};
+use ssh2::Session;
+
+use crate::menus::{
+    bundle::{Bundle, Conflict},
+    cluster::{Cluster, SessionType},
+    cluster_menu::CLUSTER_FILE,
+    connection_error::ConnectionError,
+    job::Job,
+    spawner::Spawner};
+use crate::double_column_menu::{entry::Entry, toml_list::TomlList};
+use crate::progress::Progress;
+use completions::{CompletionKind, Shell};
+use output::{
+    CancelOutput, CleanOutput, ClusterList, ClusterOutput, ErrorBody, ErrorOutput,
+    ExportOutput, ImportOutput, JobList, JobOutput, PresetList, PresetOutput, SpawnOutput};
+
+/// Exit code for a successful command.
+pub const EXIT_SUCCESS: i32 = 0;
+/// Exit code if the command failed.
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code if a cluster or preset does not exist.
+pub const EXIT_NOT_FOUND: i32 = 3;
+
+const AFTER_HELP: &str = "\
+Without a command, the terminal user interface is started.
+
+Exit codes:
+  0  success
+  1  the command failed (connection, scheduler or file errors)
+  2  invalid command line arguments
+  3  the cluster or preset does not exist";
+
+#[derive(Debug, Parser)]
+#[command(version, about, after_help = AFTER_HELP)]
+pub struct Cli {
+    /// Print the output as JSON (see the README for the schema).
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// Directory of the configuration files. Overrides
+    /// $CODE_REMOTE_CONFIG_DIR and $XDG_CONFIG_HOME/code-remote.
+    #[arg(long, global = true, value_name = "DIR")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// List the configured clusters or the presets of a cluster.
+    List {
+        #[command(subcommand)]
+        what: ListCommand,
+    },
+    /// Allocate a job for a preset (or reuse its running job) and open VS Code.
+    Spawn {
+        /// Name of the cluster.
+        cluster: String,
+        /// Name of the spawner preset.
+        preset: String,
+    },
+    /// Show the jobs of the user on a cluster.
+    Status {
+        /// Name of the cluster.
+        cluster: String,
+    },
+    /// Cancel the running job of a preset.
+    Cancel {
+        /// Name of the cluster.
+        cluster: String,
+        /// Name of the spawner preset.
+        preset: String,
+    },
+    /// Export clusters and their presets to a bundle file for sharing.
+    Export {
+        /// Names of the clusters, all clusters if none are given.
+        clusters: Vec<String>,
+        /// The bundle file to write.
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Remove the user and identity file from the exported clusters.
+        #[arg(long)]
+        strip_user: bool,
+        /// Overwrite the bundle file if it exists.
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Import the clusters and presets of a bundle file.
+    Import {
+        /// The bundle file to read.
+        file: PathBuf,
+        /// What to do with clusters that already exist. Asked for each
+        /// cluster if not given.
+        #[arg(long, value_enum)]
+        on_conflict: Option<OnConflict>,
+    },
+    /// Remove all entries that code-remote added to ~/.ssh/config.
+    CleanSshConfig,
+    /// Print the shell completion script, e.g. `source <(code-remote completions bash)`.
+    Completions {
+        shell: Shell,
+    },
+    /// Print cluster or preset names for the completion scripts.
+    #[command(name = "__complete", hide = true)]
+    Complete {
+        kind: CompletionKind,
+        /// Name of the cluster (for presets).
+        cluster: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ListCommand {
+    /// List the configured clusters.
+    Clusters,
+    /// List the spawner presets of a cluster.
+    Presets {
+        /// Name of the cluster.
+        cluster: String,
+    },
+}
+
+/// What to do with an imported cluster whose name already exists.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OnConflict {
+    /// Add the cluster under a new name, e.g. `levante(1)`.
+    Rename,
+    /// Add the presets to the existing cluster.
+    Merge,
+    /// Do not import the cluster.
+    Skip,
+}
+
+impl From<OnConflict> for Conflict {
+    fn from(choice: OnConflict) -> Conflict {
+        match choice {
+            OnConflict::Rename => Conflict::Rename,
+            OnConflict::Merge => Conflict::Merge,
+            OnConflict::Skip => Conflict::Skip,
+        }
+    }
+}
+
+/// Error for a cluster or preset that does not exist.
+#[derive(Debug)]
+pub struct NotFound(String);
+
+impl std::fmt::Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+// =======================================================================
+//            MAIN FUNCTIONS
+// =======================================================================
+
+/// Run a command and return the exit code of the program.
+pub fn run(command: Command, json: bool) -> i32 {
+    match execute(command, json) {
+        Ok(()) => EXIT_SUCCESS,
+        Err(e) => {
+            let exit_code = if e.downcast_ref::<NotFound>().is_some() {
+                EXIT_NOT_FOUND
+            } else {
+                EXIT_FAILURE
+            };
+            let connection_error = e.downcast_ref::<ConnectionError>();
+            let error = ErrorBody {
+                message: e.to_string(),
+                exit_code,
+                kind: connection_error.map(|e| e.kind()),
+                hint: connection_error.map(|e| e.remediation()),
+            };
+            output::print(&ErrorOutput { error }, json);
+            exit_code
+        }
+    }
+}
+
+fn execute(command: Command, json: bool) -> Result<()> {
+    match command {
+        Command::List { what: ListCommand::Clusters } => {
+            output::print(&list_clusters()?, json)
+        },
+        Command::List { what: ListCommand::Presets { cluster } } => {
+            output::print(&list_presets(&cluster)?, json)
+        },
+        Command::Spawn { cluster, preset } => {
+            output::print(&spawn(&cluster, &preset)?, json)
+        },
+        Command::Status { cluster } => output::print(&status(&cluster)?, json),
+        Command::Cancel { cluster, preset } => {
+            output::print(&cancel(&cluster, &preset)?, json)
+        },
+        Command::Export { clusters, output, strip_user, force } => {
+            output::print(&export(&clusters, &output, strip_user, force)?, json)
+        },
+        Command::Import { file, on_conflict } => {
+            output::print(&import(&file, on_conflict)?, json)
+        },
+        Command::CleanSshConfig => output::print(&clean_ssh_config()?, json),
+        Command::Completions { shell } => print!("{}", completions::generate(shell)),
+        Command::Complete { kind, cluster } => complete(kind, cluster.as_deref())?,
+    };
+    Ok(())
+}
+
+fn list_clusters() -> Result<ClusterList> {
+    let clusters = load_clusters()?;
+    let clusters = clusters.entry.iter().map(ClusterOutput::from).collect();
+    Ok(ClusterList { clusters })
+}
+
+fn list_presets(cluster_name: &str) -> Result<PresetList> {
+    let cluster = load_cluster(cluster_name)?;
+    let presets: TomlList<Spawner> = TomlList::load(&cluster.preset_file())?;
+    let presets = presets.entry.iter()
+        .map(|p| Ok(PresetOutput::from(&p.resolve(&presets)?)))
+        .collect::<Result<_>>()?;
+    Ok(PresetList { cluster: cluster.name, presets })
+}
+
+fn spawn(cluster_name: &str, preset_name: &str) -> Result<SpawnOutput> {
+    let cluster = load_cluster(cluster_name)?;
+    let presets: TomlList<Spawner> = TomlList::load(&cluster.preset_file())?;
+    let index = find_preset(&presets, preset_name)?;
+    let mut session = connect(&cluster)?;
+    cluster.add_cluster_to_ssh_config()?;
+
+    let result = presets.get(index)?.resolve(&presets)?.spawn(&mut session, &cluster, &Progress::stderr())?;
+    Spawner::record_job(&cluster.preset_file(), preset_name, &result.job.id)?;
+    Ok(SpawnOutput::new(&cluster.name, preset_name, &result))
+}
+
+fn status(cluster_name: &str) -> Result<JobList> {
+    let cluster = load_cluster(cluster_name)?;
+    let presets: TomlList<Spawner> = TomlList::load(&cluster.preset_file())?;
+    let session = connect(&cluster)?;
+    let jobs = Job::list(&session, &cluster)?.iter()
+        .map(|job| {
+            let preset = presets.entry.iter()
+                .find(|p| p.job_id == job.id)
+                .map(|p| p.preset_name.as_str());
+            JobOutput::new(job, preset)
+        })
+        .collect();
+    Ok(JobList { cluster: cluster.name, jobs })
+}
+
+fn cancel(cluster_name: &str, preset_name: &str) -> Result<CancelOutput> {
+    let cluster = load_cluster(cluster_name)?;
+    let presets: TomlList<Spawner> = TomlList::load(&cluster.preset_file())?;
+    let index = find_preset(&presets, preset_name)?;
+    let mut session = connect(&cluster)?;
+
+    let job_id = presets.get(index)?.resolve(&presets)?.cancel(&mut session, &cluster)?;
+    Spawner::record_job(&cluster.preset_file(), preset_name, "")?;
+    Ok(CancelOutput {
+        cluster: cluster.name,
+        preset: preset_name.to_string(),
+        job_id,
+    })
+}
+
+fn export(cluster_names: &[String], file: &Path, strip_user: bool,
+          force: bool) -> Result<ExportOutput> {
+    let clusters = load_clusters()?.entry;
+    for name in cluster_names.iter() {
+        if !clusters.iter().any(|c| &c.name == name) {
+            return Err(eyre!(NotFound(format!("Cluster {} does not exist", name))));
+        }
+    }
+    let clusters: Vec<Cluster> = clusters.into_iter()
+        .filter(|c| cluster_names.is_empty() || cluster_names.contains(&c.name))
+        .collect();
+    let names = clusters.iter().map(|c| c.name.clone()).collect();
+    Bundle::create(clusters, strip_user)?.write(file, force)?;
+    Ok(ExportOutput { file: file.display().to_string(), clusters: names })
+}
+
+fn import(file: &Path, on_conflict: Option<OnConflict>) -> Result<ImportOutput> {
+    let bundle = Bundle::read(file)?;
+    // make sure that the presets of old configurations are in place
+    load_clusters()?;
+    let clusters = bundle.import(|name| match on_conflict {
+        Some(choice) => Ok(choice.into()),
+        None => ask_for_conflict(name),
+    })?;
+    Ok(ImportOutput { clusters })
+}
+
+fn clean_ssh_config() -> Result<CleanOutput> {
+    let removed = Cluster::clean_ssh_config()?;
+    Ok(CleanOutput { removed })
+}
+
+/// Print the names for the completion scripts, one per line.
+fn complete(kind: CompletionKind, cluster_name: Option<&str>) -> Result<()> {
+    let names: Vec<String> = match kind {
+        CompletionKind::Clusters => {
+            let clusters = load_clusters()?;
+            clusters.entry.iter().map(|c| c.get_entry_name()).collect()
+        },
+        CompletionKind::Presets => {
+            let cluster_name = cluster_name
+                .ok_or_else(|| eyre!("Missing the cluster name"))?;
+            let cluster = load_cluster(cluster_name)?;
+            let presets: TomlList<Spawner> = TomlList::load(&cluster.preset_file())?;
+            presets.entry.iter().map(|p| p.get_entry_name()).collect()
+        },
+    };
+    for name in names {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+// =======================================================================
+//            HELPER FUNCTIONS
+// =======================================================================
+
+fn load_clusters() -> Result<TomlList<Cluster>> {
+    let clusters = TomlList::load(CLUSTER_FILE)?;
+    Cluster::move_legacy_presets(&clusters)?;
+    Ok(clusters)
+}
+
+fn load_cluster(name: &str) -> Result<Cluster> {
+    let clusters = load_clusters()?;
+    clusters.entry.into_iter()
+        .find(|c| c.get_entry_name() == name)
+        .ok_or_else(|| eyre!(NotFound(format!("Cluster {} does not exist", name))))
+}
+
+fn find_preset(presets: &TomlList<Spawner>, name: &str) -> Result<usize> {
+    presets.entry.iter()
+        .position(|p| p.get_entry_name() == name)
+        .ok_or_else(|| eyre!(NotFound(format!("Preset {} does not exist", name))))
+}
+
+/// Ask on the terminal what to do with a cluster that already exists.
+fn ask_for_conflict(name: &str) -> Result<Conflict> {
+    if !std::io::stdin().is_terminal() {
+        return Err(eyre!("Cluster {} already exists, use --on-conflict to \
+                          choose what to do", name));
+    }
+    loop {
+        eprint!("Cluster {} already exists: [r]ename, [m]erge presets or [s]kip? ", name);
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().lock().read_line(&mut answer)?;
+        match answer.trim() {
+            "r" | "rename" => return Ok(Conflict::Rename),
+            "m" | "merge" => return Ok(Conflict::Merge),
+            "s" | "skip" => return Ok(Conflict::Skip),
+            _ => continue,
+        }
+    }
+}
+
+/// Connect to the cluster and prompt for a password or passphrase on the
+/// terminal if required.
+fn connect(cluster: &Cluster) -> Result<Session> {
+    if let Some(warning) = cluster.certificate_warning() {
+        eprintln!("Warning: {}", warning);
+    }
+    let session_type = cluster.session_type();
+    if session_type == SessionType::Password {
+        let password = rpassword::prompt_password_stderr("Enter your password: ")?;
+        return cluster.create_session(&SessionType::Password, &password, &Progress::stderr());
+    }
+    match cluster.create_session(&session_type, "", &Progress::stderr()) {
+        Err(e) if session_type == SessionType::IdentityFile
+            && matches!(e.downcast_ref(), Some(ConnectionError::PrivateKey(_))) => {
+            let passphrase = rpassword::prompt_password_stderr("Enter your passphrase: ")?;
+            cluster.create_session(&SessionType::Passphrase, &passphrase, &Progress::stderr())
+        },
+        result => result,
+    }
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        let cli = Cli::parse_from(["code-remote", "spawn", "levante", "gpu"]);
+        assert!(matches!(cli.command,
+            Some(Command::Spawn { ref cluster, ref preset })
+            if cluster == "levante" && preset == "gpu"));
+        let cli = Cli::parse_from(["code-remote", "list", "presets", "levante"]);
+        assert!(matches!(cli.command,
+            Some(Command::List { what: ListCommand::Presets { .. } })));
+        let cli = Cli::parse_from(["code-remote"]);
+        assert!(cli.command.is_none());
+    }
+
+    #[test]
+    fn test_exit_code_not_found() {
+        let error = eyre!(NotFound("Cluster x does not exist".to_string()));
+        assert!(error.downcast_ref::<NotFound>().is_some());
+    }
+}
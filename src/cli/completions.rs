@@ -0,0 +1,213 @@
+use clap::{CommandFactory, ValueEnum};
+
+use crate::cli::Cli;
+
+/// Shells for which completion scripts can be generated.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// Names of the dynamic values that the completion scripts ask for.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum CompletionKind {
+    Clusters,
+    Presets,
+}
+
+// The scripts call `code-remote __complete clusters` and
+// `code-remote __complete presets <cluster>` to complete the names
+// from the configuration files. A `--config <DIR>` on the command line
+// is passed on to these calls. `{subcommands}` is replaced with the
+// visible subcommands of the cli.
+
+const BASH_SCRIPT: &str = r#"# bash completion for code-remote
+_code_remote() {
+    local IFS=$'\n'
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    local args=() config=() word prev=""
+    for word in "${COMP_WORDS[@]:1:COMP_CWORD-1}"; do
+        if [[ "$prev" == --config ]]; then
+            # `--config=DIR` may be split at the `=`
+            [[ "$word" == "=" ]] && continue
+            config=(--config "${word/#\~/$HOME}")
+        elif [[ "$word" == --config=* ]]; then
+            word="${word#--config=}"
+            config=(--config "${word/#\~/$HOME}")
+        elif [[ "$word" != -* ]]; then
+            args+=("$word")
+        fi
+        prev="$word"
+    done
+    if [[ "$prev" == --config ]]; then
+        COMPREPLY=($(compgen -d -- "$cur")); return
+    fi
+    local candidates=""
+    case "${#args[@]}" in
+        0) candidates="{subcommands}" ;;
+        1) case "${args[0]}" in
+               list) candidates=$'clusters\npresets' ;;
+               spawn|status|cancel|export) candidates="$(code-remote "${config[@]}" __complete clusters 2>/dev/null)" ;;
+               import) COMPREPLY=($(compgen -f -- "$cur")); return ;;
+               completions) candidates=$'bash\nzsh\nfish' ;;
+           esac ;;
+        2) case "${args[0]}" in
+               list) [[ "${args[1]}" == presets ]] \
+                   && candidates="$(code-remote "${config[@]}" __complete clusters 2>/dev/null)" ;;
+               spawn|cancel) candidates="$(code-remote "${config[@]}" __complete presets "${args[1]}" 2>/dev/null)" ;;
+           esac ;;
+    esac
+    COMPREPLY=($(compgen -W "$candidates" -- "$cur"))
+}
+complete -F _code_remote code-remote
+"#;
+
+const ZSH_SCRIPT: &str = r#"#compdef code-remote
+# zsh completion for code-remote
+_code_remote() {
+    local -a args candidates config
+    local i word
+    for (( i = 2; i < CURRENT; i++ )); do
+        word=$words[i]
+        if [[ $words[i-1] == --config ]]; then
+            config=(--config ${word/#\~/$HOME})
+        elif [[ $word == --config=* ]]; then
+            word=${word#--config=}
+            config=(--config ${word/#\~/$HOME})
+        elif [[ $word != -* ]]; then
+            args+=($word)
+        fi
+    done
+    if [[ $words[CURRENT-1] == --config ]]; then
+        _directories; return
+    fi
+    case ${#args} in
+        0) candidates=({subcommands}) ;;
+        1) case $args[1] in
+               list) candidates=(clusters presets) ;;
+               spawn|status|cancel|export) candidates=(${(f)"$(code-remote $config __complete clusters 2>/dev/null)"}) ;;
+               import) _files; return ;;
+               completions) candidates=(bash zsh fish) ;;
+           esac ;;
+        2) case $args[1] in
+               list) [[ $args[2] == presets ]] \
+                   && candidates=(${(f)"$(code-remote $config __complete clusters 2>/dev/null)"}) ;;
+               spawn|cancel) candidates=(${(f)"$(code-remote $config __complete presets $args[2] 2>/dev/null)"}) ;;
+           esac ;;
+    esac
+    compadd -a candidates
+}
+compdef _code_remote code-remote
+"#;
+
+const FISH_SCRIPT: &str = r#"# fish completion for code-remote
+function __code_remote_args
+    set -l tokens (commandline -opc)
+    set -e tokens[1]
+    set -l skip 0
+    for token in $tokens
+        if test $skip -eq 1
+            set skip 0
+        else if test "$token" = --config
+            set skip 1
+        else if not string match -q -- '-*' $token
+            echo $token
+        end
+    end
+end
+
+# the `--config DIR` option of the command line, if any
+function __code_remote_config
+    set -l tokens (commandline -opc)
+    set -l config
+    for i in (seq 2 (count $tokens))
+        if test "$tokens[(math $i - 1)]" = --config
+            set config $tokens[$i]
+        else if string match -q -- '--config=*' $tokens[$i]
+            set config (string replace -- '--config=' '' $tokens[$i])
+        end
+    end
+    if set -q config[1]
+        echo --config
+        string replace -r -- '^~' $HOME $config
+    end
+end
+
+# true if there are exactly $argv[1] arguments and the first one is in $argv[2..]
+function __code_remote_at
+    set -l args (__code_remote_args)
+    test (count $args) -eq $argv[1]; and contains -- $args[1] $argv[2..-1]
+end
+
+complete -c code-remote -f
+complete -c code-remote -n 'test (count (__code_remote_args)) -eq 0' -a '{subcommands}'
+complete -c code-remote -n '__code_remote_at 1 list' -a 'clusters presets'
+complete -c code-remote -n '__code_remote_at 1 spawn status cancel export' -a '(code-remote (__code_remote_config) __complete clusters 2>/dev/null)'
+complete -c code-remote -n '__code_remote_at 1 import' -F
+complete -c code-remote -n '__code_remote_at 1 completions' -a 'bash zsh fish'
+complete -c code-remote -n '__code_remote_at 2 list; and test (__code_remote_args)[2] = presets' -a '(code-remote (__code_remote_config) __complete clusters 2>/dev/null)'
+complete -c code-remote -n '__code_remote_at 2 spawn cancel' -a '(code-remote (__code_remote_config) __complete presets (__code_remote_args)[2] 2>/dev/null)'
+complete -c code-remote -l config -r -a '(__fish_complete_directories)'
+"#;
+
+/// Get the names of the visible subcommands.
+fn get_subcommands() -> Vec<String> {
+    let mut names: Vec<String> = Cli::command().get_subcommands()
+        .filter(|c| !c.is_hide_set())
+        .map(|c| c.get_name().to_string())
+        .collect();
+    names.push("help".to_string());
+    names
+}
+
+/// Generate the completion script for the given shell.
+pub fn generate(shell: Shell) -> String {
+    let subcommands = get_subcommands();
+    match shell {
+        Shell::Bash => BASH_SCRIPT.replace("{subcommands}", &subcommands.join("\n")),
+        Shell::Zsh => ZSH_SCRIPT.replace("{subcommands}", &subcommands.join(" ")),
+        Shell::Fish => FISH_SCRIPT.replace("{subcommands}", &subcommands.join(" ")),
+    }
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_subcommands() {
+        let subcommands = get_subcommands();
+        assert!(subcommands.contains(&"spawn".to_string()));
+        assert!(subcommands.contains(&"completions".to_string()));
+        assert!(!subcommands.contains(&"__complete".to_string()));
+    }
+
+    #[test]
+    fn test_generate() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let script = generate(shell);
+            assert!(!script.contains("{subcommands}"));
+            assert!(script.contains("__complete presets"));
+        }
+    }
+
+    #[test]
+    fn test_bash_forwards_config() {
+        // replace the binary by a function that prints its arguments
+        let script = format!(
+            "{}\ncode-remote() {{ echo \"$@\"; }}\n\
+             COMP_WORDS=(code-remote --config '~/cfg' spawn levante '')\n\
+             COMP_CWORD=5\n_code_remote\necho \"${{COMPREPLY[0]}}\"",
+            generate(Shell::Bash));
+        let output = std::process::Command::new("bash").arg("-c").arg(script)
+            .env("HOME", "/home/u").output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(),
+                   "--config /home/u/cfg __complete presets levante");
+    }
+}
@@ -0,0 +1,299 @@
+use serde::Serialize;
+
+use crate::menus::{
+    bundle::{ImportAction, ImportedCluster},
+    cluster::Cluster,
+    job::Job,
+    spawner::{SpawnResult, Spawner}};
+
+/// Version of the JSON schema. It is increased on every incompatible
+/// change of the output, adding fields is not considered incompatible.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Output of a command, either printed as text or as JSON.
+pub trait Output: Serialize {
+    /// Print the output in a human readable form.
+    fn print_text(&self);
+}
+
+/// Every JSON document carries the schema version next to the data.
+#[derive(Serialize)]
+struct Envelope<'a, T: Serialize> {
+    schema_version: u32,
+    #[serde(flatten)]
+    data: &'a T,
+}
+
+/// Print the output of a command to stdout.
+pub fn print<T: Output>(output: &T, json: bool) {
+    if json {
+        let envelope = Envelope { schema_version: SCHEMA_VERSION, data: output };
+        println!("{}", serde_json::to_string_pretty(&envelope)
+                 .expect("output is always serializable"));
+    } else {
+        output.print_text();
+    }
+}
+
+// =======================================================================
+//            ENTRIES
+// =======================================================================
+
+#[derive(Debug, Serialize)]
+pub struct ClusterOutput {
+    pub name: String,
+    pub host: String,
+    pub user: String,
+    pub identity_file: String,
+    pub certificate_file: String,
+}
+
+impl From<&Cluster> for ClusterOutput {
+    fn from(cluster: &Cluster) -> Self {
+        ClusterOutput {
+            name: cluster.name.clone(),
+            host: cluster.host.clone(),
+            user: cluster.user.clone(),
+            identity_file: cluster.identity_file.clone(),
+            certificate_file: cluster.certificate_file.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PresetOutput {
+    pub name: String,
+    pub account: String,
+    pub partition: String,
+    pub time: String,
+    pub working_directory: String,
+    pub job_id: Option<String>,
+}
+
+impl From<&Spawner> for PresetOutput {
+    fn from(spawner: &Spawner) -> Self {
+        PresetOutput {
+            name: spawner.preset_name.clone(),
+            account: spawner.account.clone(),
+            partition: spawner.partition.clone(),
+            time: spawner.time.clone(),
+            working_directory: spawner.working_directory.clone(),
+            job_id: non_empty(&spawner.job_id),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobOutput {
+    pub id: String,
+    pub name: String,
+    pub state: String,
+    pub node: Option<String>,
+    pub partition: String,
+    pub time: String,
+    /// The preset that recorded this job, if any.
+    pub preset: Option<String>,
+}
+
+impl JobOutput {
+    pub fn new(job: &Job, preset: Option<&str>) -> Self {
+        JobOutput {
+            id: job.id.clone(),
+            name: job.name.clone(),
+            state: job.state.clone(),
+            node: non_empty(&job.node),
+            partition: job.partition.clone(),
+            time: job.time.clone(),
+            preset: preset.map(|p| p.to_string()),
+        }
+    }
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    if value.is_empty() { None } else { Some(value.to_string()) }
+}
+
+// =======================================================================
+//            COMMAND OUTPUTS
+// =======================================================================
+
+#[derive(Debug, Serialize)]
+pub struct ClusterList {
+    pub clusters: Vec<ClusterOutput>,
+}
+
+impl Output for ClusterList {
+    fn print_text(&self) {
+        for cluster in self.clusters.iter() {
+            println!("{}\t{}@{}", cluster.name, cluster.user, cluster.host);
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PresetList {
+    pub cluster: String,
+    pub presets: Vec<PresetOutput>,
+}
+
+impl Output for PresetList {
+    fn print_text(&self) {
+        for preset in self.presets.iter() {
+            println!("{}\t{}\t{}\t{}", preset.name, preset.partition,
+                     preset.time, preset.job_id.as_deref().unwrap_or(""));
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobList {
+    pub cluster: String,
+    pub jobs: Vec<JobOutput>,
+}
+
+impl Output for JobList {
+    fn print_text(&self) {
+        for job in self.jobs.iter() {
+            println!("{:<10} {:<20} {:<10} {:<12} {:<10} {}",
+                     job.id, job.name, job.state,
+                     job.node.as_deref().unwrap_or(""), job.time,
+                     job.preset.as_deref().unwrap_or("-"));
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SpawnOutput {
+    pub cluster: String,
+    pub preset: String,
+    pub job_id: String,
+    pub node: String,
+    pub alias: String,
+    pub uris: Vec<String>,
+}
+
+impl SpawnOutput {
+    pub fn new(cluster: &str, preset: &str, result: &SpawnResult) -> Self {
+        SpawnOutput {
+            cluster: cluster.to_string(),
+            preset: preset.to_string(),
+            job_id: result.job.id.clone(),
+            node: result.job.node.clone(),
+            alias: result.alias.clone(),
+            uris: result.uris.clone(),
+        }
+    }
+}
+
+impl Output for SpawnOutput {
+    fn print_text(&self) {
+        println!("Spawned {} on {} (job {})", self.preset, self.node, self.job_id);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CancelOutput {
+    pub cluster: String,
+    pub preset: String,
+    pub job_id: String,
+}
+
+impl Output for CancelOutput {
+    fn print_text(&self) {
+        println!("Cancelled job {}", self.job_id);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportOutput {
+    pub file: String,
+    pub clusters: Vec<String>,
+}
+
+impl Output for ExportOutput {
+    fn print_text(&self) {
+        println!("Exported {} clusters to {}", self.clusters.len(), self.file);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportOutput {
+    pub clusters: Vec<ImportedCluster>,
+}
+
+impl Output for ImportOutput {
+    fn print_text(&self) {
+        for cluster in self.clusters.iter() {
+            match cluster.action {
+                ImportAction::Added => println!(
+                    "Added {} with {} presets", cluster.name, cluster.presets),
+                ImportAction::Renamed => println!(
+                    "Added {} as {} with {} presets",
+                    cluster.bundle_name, cluster.name, cluster.presets),
+                ImportAction::Merged => println!(
+                    "Added {} presets to {}", cluster.presets, cluster.name),
+                ImportAction::Skipped => println!("Skipped {}", cluster.name),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CleanOutput {
+    pub removed: usize,
+}
+
+impl Output for CleanOutput {
+    fn print_text(&self) {
+        println!("Removed {} entries from ~/.ssh/config", self.removed);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub message: String,
+    pub exit_code: i32,
+    /// The kind of a connection error, e.g. `dns` or `auth_method`.
+    pub kind: Option<&'static str>,
+    /// What the user can do about a connection error.
+    pub hint: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorOutput {
+    pub error: ErrorBody,
+}
+
+impl Output for ErrorOutput {
+    fn print_text(&self) {
+        eprintln!("Error: {}", self.error.message);
+        if let Some(hint) = &self.error.hint {
+            eprintln!("{}", hint);
+        }
+    }
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope() {
+        let output = CleanOutput { removed: 2 };
+        let envelope = Envelope { schema_version: SCHEMA_VERSION, data: &output };
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(json, serde_json::json!({"schema_version": 1, "removed": 2}));
+    }
+
+    #[test]
+    fn test_preset_output() {
+        let spawner = Spawner::new("gpu", "acc", "gpu", "01:00:00", "", "");
+        let json = serde_json::to_value(PresetOutput::from(&spawner)).unwrap();
+        assert_eq!(json["name"], "gpu");
+        assert_eq!(json["job_id"], serde_json::Value::Null);
+    }
+}
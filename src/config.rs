@@ -0,0 +1,137 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use color_eyre::eyre::{eyre, Result};
+
+/// Environment variable that overrides the configuration directory.
+pub const CONFIG_DIR_ENV: &str = "CODE_REMOTE_CONFIG_DIR";
+
+/// Configuration directory given on the command line with `--config`.
+static CONFIG_DIR_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set the configuration directory from the command line. It takes
+/// precedence over all environment variables.
+pub fn set_config_dir(path: &Path) {
+    let _ = CONFIG_DIR_OVERRIDE.set(path.to_path_buf());
+}
+
+/// Get the directory that contains the configuration files.
+///
+/// The directory is, in this order of precedence:
+/// 1. the `--config` command line flag
+/// 2. `$CODE_REMOTE_CONFIG_DIR`
+/// 3. `$XDG_CONFIG_HOME/code-remote`
+/// 4. `$HOME/.config/code-remote`
+pub fn config_dir() -> Result<PathBuf> {
+    resolve_config_dir(
+        CONFIG_DIR_OVERRIDE.get().map(|p| p.as_path()),
+        std::env::var_os(CONFIG_DIR_ENV),
+        std::env::var_os("XDG_CONFIG_HOME"),
+        std::env::var_os("HOME"))
+}
+
+/// Resolve the configuration directory from the given sources.
+/// Empty environment variables are ignored.
+pub fn resolve_config_dir(
+    flag: Option<&Path>,
+    env_dir: Option<OsString>,
+    xdg_config_home: Option<OsString>,
+    home: Option<OsString>) -> Result<PathBuf> {
+    let non_empty = |value: Option<OsString>| value.filter(|v| !v.is_empty());
+    if let Some(path) = flag {
+        return Ok(path.to_path_buf());
+    }
+    if let Some(dir) = non_empty(env_dir) {
+        return Ok(PathBuf::from(dir));
+    }
+    if let Some(dir) = non_empty(xdg_config_home) {
+        return Ok(PathBuf::from(dir).join("code-remote"));
+    }
+    let home = non_empty(home)
+        .ok_or_else(|| eyre!("Could not find the home directory"))?;
+    Ok(PathBuf::from(home).join(".config").join("code-remote"))
+}
+
+/// Get the directory for files that code-remote writes for
+/// troubleshooting, like the log of the last run.
+///
+/// If the configuration directory is set explicitly (`--config` or
+/// `$CODE_REMOTE_CONFIG_DIR`), these files are kept next to the
+/// configuration. Otherwise the directory is `$XDG_STATE_HOME/code-remote`
+/// or `$HOME/.local/state/code-remote`.
+pub fn state_dir() -> Result<PathBuf> {
+    resolve_state_dir(
+        CONFIG_DIR_OVERRIDE.get().map(|p| p.as_path()),
+        std::env::var_os(CONFIG_DIR_ENV),
+        std::env::var_os("XDG_STATE_HOME"),
+        std::env::var_os("HOME"))
+}
+
+/// Resolve the state directory from the given sources.
+/// Empty environment variables are ignored.
+pub fn resolve_state_dir(
+    flag: Option<&Path>,
+    env_dir: Option<OsString>,
+    xdg_state_home: Option<OsString>,
+    home: Option<OsString>) -> Result<PathBuf> {
+    let non_empty = |value: Option<OsString>| value.filter(|v| !v.is_empty());
+    if flag.is_some() || non_empty(env_dir.clone()).is_some() {
+        return resolve_config_dir(flag, env_dir, None, None);
+    }
+    if let Some(dir) = non_empty(xdg_state_home) {
+        return Ok(PathBuf::from(dir).join("code-remote"));
+    }
+    let home = non_empty(home)
+        .ok_or_else(|| eyre!("Could not find the home directory"))?;
+    Ok(PathBuf::from(home).join(".local").join("state").join("code-remote"))
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_config_dir() {
+        let home = Some(OsString::from("/home/user"));
+        let xdg = Some(OsString::from("/xdg"));
+        let env = Some(OsString::from("/env"));
+        assert_eq!(resolve_config_dir(None, None, None, home.clone()).unwrap(),
+                   PathBuf::from("/home/user/.config/code-remote"));
+        assert_eq!(resolve_config_dir(None, None, xdg.clone(), home.clone()).unwrap(),
+                   PathBuf::from("/xdg/code-remote"));
+        assert_eq!(resolve_config_dir(None, env.clone(), xdg.clone(), home.clone()).unwrap(),
+                   PathBuf::from("/env"));
+        assert_eq!(resolve_config_dir(Some(Path::new("/flag")), env, xdg, home).unwrap(),
+                   PathBuf::from("/flag"));
+    }
+
+    #[test]
+    fn test_resolve_config_dir_ignores_empty() {
+        let empty = Some(OsString::new());
+        let home = Some(OsString::from("/home/user"));
+        assert_eq!(resolve_config_dir(None, empty.clone(), empty.clone(), home).unwrap(),
+                   PathBuf::from("/home/user/.config/code-remote"));
+        assert!(resolve_config_dir(None, None, None, empty).is_err());
+    }
+
+    #[test]
+    fn test_resolve_state_dir() {
+        let home = Some(OsString::from("/home/user"));
+        let xdg = Some(OsString::from("/xdg"));
+        let env = Some(OsString::from("/env"));
+        assert_eq!(resolve_state_dir(None, None, None, home.clone()).unwrap(),
+                   PathBuf::from("/home/user/.local/state/code-remote"));
+        assert_eq!(resolve_state_dir(None, None, xdg.clone(), home.clone()).unwrap(),
+                   PathBuf::from("/xdg/code-remote"));
+        assert_eq!(resolve_state_dir(None, env, xdg.clone(), home.clone()).unwrap(),
+                   PathBuf::from("/env"));
+        assert_eq!(resolve_state_dir(Some(Path::new("/flag")), None, xdg, home).unwrap(),
+                   PathBuf::from("/flag"));
+    }
+}
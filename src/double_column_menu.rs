@@ -1,5 +1,6 @@
 pub mod entry;
 pub mod counter;
+#[allow(clippy::module_inception)]
 pub mod double_column_menu;
 pub mod toml_list;
 pub mod render_helper_functions;
@@ -24,6 +24,11 @@ impl Counter {
         }
     }
 
+    /// Set the value, it is limited to the length of the counter.
+    pub fn set_value(&mut self, value: u32) {
+        self.value = value.min(self.length.saturating_sub(1));
+    }
+
     pub fn increment(&mut self) {
         self.value += 1;
         if self.value >= self.length {
@@ -79,6 +84,15 @@ mod tests {
         assert_eq!(counter.value, 0);
     }
 
+    #[test]
+    fn test_set_value() {
+        let mut counter = Counter::new(3);
+        counter.set_value(1);
+        assert_eq!(counter.value, 1);
+        counter.set_value(5);
+        assert_eq!(counter.value, 2);
+    }
+
     #[test]
     fn test_update_length() {
         let mut counter = Counter::new(3);
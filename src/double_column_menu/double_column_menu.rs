@@ -1,17 +1,20 @@
 use serde::{Serialize, Deserialize};
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
 use std::default::Default;
 use ratatui::{prelude::*, widgets::*};
 use crossterm::event::{KeyCode, KeyEvent};
 use tui_textarea::{TextArea};
 
 use crate::double_column_menu::{
-    entry::Entry,
+    entry::{Entry, EntryChange, unique_name},
     counter::Counter,
-    toml_list::TomlList,
+    toml_list::{Migrate, TomlList},
     render_helper_functions::*,};
 use crate::app::Action;
 
+/// The list entry that creates a new entry.
+const CREATE_NEW: &str = "Create New";
+
 #[derive(Debug, Default, PartialEq)]
 pub enum Focus {
     #[default]
@@ -27,7 +30,7 @@ pub enum InputMode {
     Remove,
 }
 
-pub trait DoubleColumnMenu<T: Serialize + for<'a> Deserialize<'a> + PartialEq + Entry + Default> {
+pub trait DoubleColumnMenu<T: Serialize + for<'a> Deserialize<'a> + PartialEq + Entry + Default + Migrate + Clone> {
 // =======================================================================
 //  METHODS TO IMPLEMENT
 // =======================================================================
@@ -47,6 +50,35 @@ pub trait DoubleColumnMenu<T: Serialize + for<'a> Deserialize<'a> + PartialEq +
     fn action_right(&mut self, action: &mut Action);
     fn action_left(&mut self, action: &mut Action);
 
+    /// Handle menu specific keys in normal mode.
+    fn input_other(&mut self, _action: &mut Action, _key_event: KeyEvent) {}
+
+    /// Read-only entries from other sources. They are listed below the
+    /// own entries and can be copied into the own list.
+    fn get_shared_entries(&self) -> &[T] {
+        &[]
+    }
+
+    /// The values of the selected entry as shown in the info pane.
+    fn get_display_values(&self) -> Vec<String> {
+        self.get_entry().map(|e| e.get_entry_values()).unwrap_or_default()
+    }
+
+    /// The additional lines below the values in the info pane.
+    fn get_display_info_text(&self) -> Vec<String> {
+        self.get_entry().map(|e| e.get_info_text()).unwrap_or_default()
+    }
+
+    /// The invalid fields of the selected entry as shown in the info pane.
+    fn get_display_errors(&self) -> Vec<(usize, String)> {
+        self.get_entry().map(|e| e.get_field_errors()).unwrap_or_default()
+    }
+
+    /// Clean up the files that belong to a removed entry.
+    fn on_remove(&mut self, _entry: &T) -> Result<()> {
+        Ok(())
+    }
+
 // =======================================================================
 //  DEFAULT METHODS
 // =======================================================================
@@ -59,8 +91,13 @@ pub trait DoubleColumnMenu<T: Serialize + for<'a> Deserialize<'a> + PartialEq +
     // -----------------------------------------------------------------------
     
     fn get_entry(&self) -> Result<&T> {
-        let index = self.get_list_counter().get_value();
-        self.get_entries().get(index as usize)
+        let index = self.get_list_counter().get_value() as usize;
+        let length = self.get_entries().len();
+        if index > length {
+            return self.get_shared_entries().get(index - length - 1)
+                .ok_or_else(|| eyre!("Index out of bounds."));
+        }
+        self.get_entries().get(index)
     }
 
     fn get_entry_mut(&mut self) -> Result<&mut T> {
@@ -72,26 +109,36 @@ pub trait DoubleColumnMenu<T: Serialize + for<'a> Deserialize<'a> + PartialEq +
         let entries = self.get_entries();
         let mut spawn_list: Vec<String> = entries.entry
             .iter().map(|c| c.get_entry_name()).collect();
-        spawn_list.push("Create New".to_string());
+        spawn_list.push(CREATE_NEW.to_string());
+        spawn_list.extend(self.get_shared_entries().iter()
+            .map(|c| format!("[shared] {}", c.get_entry_name())));
         spawn_list
     }
 
     fn get_input_buffer(&self) -> String {
         let index = self.get_info_counter().get_value() as usize;
-        let entry = self.get_entry().unwrap();
-        entry.get_value_from_index(index)
+        self.get_entry().map(|e| e.get_value_from_index(index)).unwrap_or_default()
     }
 
-    fn set_input_buffer(&mut self, value: &str) {
+    fn set_input_buffer(&mut self, value: &str) -> Result<()> {
         let index = self.get_info_counter().get_value() as usize;
-        let new_name = if index == 0 {
+        let value = if index == 0 {
             self.check_entry_name(value)
         } else {
             value.to_string()
         };
-        let entry = self.get_entry_mut().unwrap();
-        entry.set_value_from_index(index, &new_name);
-        self.save_entries().unwrap();
+        let name = self.get_entry()?.get_entry_name();
+        let name = self.apply_change(EntryChange::Set { name, index, value })?;
+        self.select_entry(&name);
+        Ok(())
+    }
+
+    /// Select the own entry with the given name, if it exists.
+    fn select_entry(&mut self, name: &str) {
+        if let Some(index) = self.get_entries().entry.iter()
+            .position(|e| e.get_entry_name() == name) {
+            self.get_list_counter_mut().set_value(index as u32);
+        }
     }
 
     // -----------------------------------------------------------------------
@@ -100,71 +147,93 @@ pub trait DoubleColumnMenu<T: Serialize + for<'a> Deserialize<'a> + PartialEq +
 
     fn add_entry(&mut self, entry: T){
         self.get_entries_mut().push(entry);
-        let list_length = self.get_entries().len() as u32;
-        self.get_list_counter_mut().update_length(list_length+1);
+        self.update_list_length();
+    }
+
+    /// Copy the selected shared entry into the own list and select it.
+    fn copy_shared_entry(&mut self) -> Result<()> {
+        let entry = self.get_entry()?.clone();
+        let name = self.apply_change(EntryChange::Add(entry))?;
+        self.select_entry(&name);
+        Ok(())
     }
 
-    fn add_new_entry(&mut self) {
+    fn add_new_entry(&mut self) -> Result<()> {
         let mut new_entry = T::default();
         new_entry.set_entry_name(&self.check_entry_name("New Entry"));
-        self.add_entry(new_entry);
+        let name = self.apply_change(EntryChange::Add(new_entry))?;
+        self.select_entry(&name);
+        Ok(())
     }
 
-    fn remove_selected(&mut self) {
+    fn remove_selected(&mut self) -> Result<()> {
         let index = self.get_list_counter().get_value() as usize;
-        self.get_entries_mut().entry.remove(index);
-        let list_length = self.get_entries().len() as u32;
-        self.get_list_counter_mut().update_length(list_length+1);
+        if index >= self.get_entries().len() {
+            return Err(eyre!("Index out of bounds."));
+        }
+        let entry = self.get_entries().get(index)?.clone();
         // reset the focus to the list
         *self.get_focus_mut() = Focus::List;
         *self.get_input_mode_mut() = InputMode::Normal;
-        self.save_entries().unwrap();
+        self.apply_change(EntryChange::Remove(entry.get_entry_name()))?;
+        self.on_remove(&entry)
     }
 
     // -----------------------------------------------------------------------
     //  CHECKERS
     // -----------------------------------------------------------------------
 
+    fn is_shared_entry(&self) -> bool {
+        let counter = self.get_list_counter().get_value();
+        let length = self.get_entries().len() as u32;
+        counter > length
+    }
+
     fn is_new_entry(&self) -> bool {
         let counter = self.get_list_counter().get_value();
         let length = self.get_entries().len() as u32;
         counter == length
     }
     
+    /// A name for an additional entry, e.g. an imported one, that does
+    /// not exist in the list yet.
+    fn new_entry_name(&self, name: &str) -> String {
+        let mut name_list = self.get_entry_names();
+        name_list.retain(|c| c != CREATE_NEW);
+        unique_name(name, &name_list)
+    }
+
     /// Check if a new name is valid. E.g. it is not empty and 
     /// it does not exist in the list of entries.
     /// If the name is not valid, it returns a modified name:
     /// new name = name + "(i)"  where i is the smallest integer such that
     /// the new name does not exist in the list of entries.
     fn check_entry_name(&self, name: &str) -> String {
-        let mut new_name = name.to_string();
-        let mut i = 1;
-        let mut name_list = self.get_entry_names();
-        // We need to remove the current selected entry from the list
-        if !self.is_new_entry() {
-            let old_name = self.get_entry().unwrap().get_entry_name();
-            name_list.retain(|c| c != &old_name);
-        } else {
-            name_list.pop();
-        }
-        while name_list.contains(&new_name) {
-            new_name = format!("{}({})", name, i);
-            i += 1;
+        match (self.is_new_entry(), self.get_entry()) {
+            // the selected entry may keep its name
+            (false, Ok(entry)) => {
+                let old_name = entry.get_entry_name();
+                let mut name_list = self.get_entry_names();
+                name_list.retain(|c| c != &old_name);
+                unique_name(name, &name_list)
+            },
+            _ => self.new_entry_name(name),
         }
-        new_name
     }
 
     // =======================================================================
     //            FILE OPERATIONS
     // =======================================================================
 
-    fn save_entries(&mut self) -> Result<()> {
-        let filename: String;
-        {
-            filename = self.get_filename().to_string();
-        }
-        let entries = self.get_entries_mut();
-        entries.save(&filename)
+    /// Apply a change to the latest version of the file while holding its
+    /// lock, such that the changes of other instances are not overwritten.
+    /// The entries are reloaded afterwards. Returns the name of the
+    /// changed entry.
+    fn apply_change(&mut self, change: EntryChange<T>) -> Result<String> {
+        let filename = self.get_filename().to_string();
+        let name = TomlList::modify(&filename, |entries| change.apply(entries))??;
+        self.load_entries()?;
+        Ok(name)
     }
 
     fn load_entries(&mut self) -> Result<()> {
@@ -175,11 +244,19 @@ pub trait DoubleColumnMenu<T: Serialize + for<'a> Deserialize<'a> + PartialEq +
         let entries = self.get_entries_mut();
         let new_entries = TomlList::load(&filename)?;
         entries.set_list(new_entries.entry);
-        let entry_len = entries.len() as u32;
-        self.get_list_counter_mut().update_length(entry_len + 1);
+        entries.defaults = new_entries.defaults;
+        entries.migrated_from = new_entries.migrated_from;
+        self.update_list_length();
         Ok(())
     }
 
+    /// Update the length of the list counter: the own entries, the
+    /// "Create New" entry and the shared entries.
+    fn update_list_length(&mut self) {
+        let length = self.get_entries().len() + 1 + self.get_shared_entries().len();
+        self.get_list_counter_mut().update_length(length as u32);
+    }
+
     // =======================================================================
     //            Rendering
     // =======================================================================
@@ -230,21 +307,47 @@ pub trait DoubleColumnMenu<T: Serialize + for<'a> Deserialize<'a> + PartialEq +
         // and control information in the bottom
         let vertical_layout = vertical_split_fixed(&inner_area, 1);
 
+        let Ok(entry) = self.get_entry() else {
+            return;
+        };
+
+        // reserve space for the additional info text below the fields
+        let info_text = self.get_display_info_text();
+        let info_layout = vertical_split_fixed(
+            &vertical_layout[0], info_text.len() as u16);
+        render_list(f, &info_layout[1], info_text, false, 0, "  ");
+
         // create a layout for the inner area
-        let layout = horizontal_split_fixed(&vertical_layout[0], 15);
+        let layout = horizontal_split_fixed(&info_layout[0], 15);
 
         let enable_highlight = focus == &Focus::Info;
 
-        let entry = self.get_entry().unwrap();
         let counter = self.get_info_counter().get_value() as usize;
 
-        render_list(f, &layout[0], entry.get_entry_names(), enable_highlight, 
-                    counter, "  ");
-        render_list(f, &layout[1], entry.get_entry_values(), enable_highlight, 
-                    counter, "  ");
+        // mark the invalid fields in red, with the error after the value
+        let errors = self.get_display_errors();
+        let error_of = |index: usize| errors.iter()
+            .find(|(field, _)| *field == index).map(|(_, message)| message);
+        let error_style = Style::default().fg(Color::Red);
+        let names: Vec<ListItem> = entry.get_entry_names().into_iter().enumerate()
+            .map(|(i, name)| match error_of(i) {
+                Some(_) => ListItem::new(name).style(error_style),
+                None => ListItem::new(name),
+            })
+            .collect();
+        let values: Vec<ListItem> = self.get_display_values().into_iter().enumerate()
+            .map(|(i, value)| match error_of(i) {
+                Some(message) => ListItem::new(format!("{}  ! {}", value, message))
+                    .style(error_style),
+                None => ListItem::new(value),
+            })
+            .collect();
+        render_list(f, &layout[0], names, enable_highlight, counter, "  ");
+        render_list(f, &layout[1], values, enable_highlight, counter, "  ");
 
         let control_info_text = match self.get_input_mode() {
             InputMode::Editing => "Press `Enter` to save, `Esc` to cancel.",
+            _ if self.is_shared_entry() => "Press `Enter` to copy it to your list.",
             _ => match self.get_focus() {
                 Focus::List => "Press `Enter` to select, 'd' to delete.",
                 Focus::Info => "Press `Enter` to edit.",
@@ -285,43 +388,51 @@ pub trait DoubleColumnMenu<T: Serialize + for<'a> Deserialize<'a> + PartialEq +
     //           INPUT HANDLING
     // =======================================================================
 
+    /// Handle the input. Failures, e.g. when saving the file, are passed
+    /// on as an action such that the app shows them.
     fn input(&mut self, action: &mut Action, key_event: KeyEvent) {
-        match self.get_input_mode() {
+        let result = match self.get_input_mode() {
             InputMode::Normal => self.input_normal_mode(action, key_event),
             InputMode::Remove => self.input_remove_mode(key_event),
             InputMode::Editing => self.input_editing_mode(key_event),
+        };
+        if let Err(e) = result {
+            *action = Action::ShowError(e.to_string());
         }
     }
 
-    fn input_normal_mode(&mut self, action: &mut Action, key_event: KeyEvent) {
+    fn input_normal_mode(&mut self, action: &mut Action, key_event: KeyEvent) -> Result<()> {
         match key_event.code {
             KeyCode::Char('q') => *action = Action::Quit,
+            KeyCode::Char('o') => *action = Action::OpenLog,
             KeyCode::Tab => self.toggle_focus(),
             KeyCode::Down | KeyCode::Char('j') => self.on_down(),
             KeyCode::Up | KeyCode::Char('k') => self.on_up(),
             KeyCode::Right | KeyCode::Char('l') => self.on_right(action),
             KeyCode::Left | KeyCode::Char('h') => self.on_left(action),
-            KeyCode::Enter => self.on_enter(action),
+            KeyCode::Enter => return self.on_enter(action),
             KeyCode::Char('d') => self.open_remove_mode(),
             KeyCode::Char('i') => self.open_input_mode(),
-            _ => {}
+            _ => self.input_other(action, key_event),
         };
+        Ok(())
     }
 
-    fn input_remove_mode(&mut self, key_event: KeyEvent) {
+    fn input_remove_mode(&mut self, key_event: KeyEvent) -> Result<()> {
         match key_event.code {
-            KeyCode::Enter | KeyCode::Char('y') => self.remove_selected(),
+            KeyCode::Enter | KeyCode::Char('y') => return self.remove_selected(),
             KeyCode::Esc | KeyCode::Char('n') => {
                 *self.get_input_mode_mut() = InputMode::Normal;
             }
             _ => {}
         };
+        Ok(())
     }
 
-    fn input_editing_mode(&mut self, key_event: KeyEvent) {
+    fn input_editing_mode(&mut self, key_event: KeyEvent) -> Result<()> {
         match key_event.code {
             KeyCode::Enter => {
-                self.close_input_mode()
+                return self.close_input_mode();
             },
             KeyCode::Esc => {
                 *self.get_input_mode_mut() = InputMode::Normal;
@@ -330,11 +441,12 @@ pub trait DoubleColumnMenu<T: Serialize + for<'a> Deserialize<'a> + PartialEq +
                 self.get_text_area().input(key_event);
             }
         };
+        Ok(())
     }
 
     fn on_right(&mut self, action: &mut Action) {
-        // do nothing if the current entry is a new entry
-        if self.is_new_entry() {
+        // do nothing if the current entry is a new or shared entry
+        if self.is_new_entry() || self.is_shared_entry() {
             return;
         }
         // do nothing if the focus is on the info section
@@ -374,20 +486,24 @@ pub trait DoubleColumnMenu<T: Serialize + for<'a> Deserialize<'a> + PartialEq +
         }
     }
 
-    fn on_enter(&mut self, action: &mut Action) {
+    fn on_enter(&mut self, action: &mut Action) -> Result<()> {
         // check if the current entry is a new entry
         if self.is_new_entry() {
-            self.add_new_entry();
+            self.add_new_entry()?;
             *self.get_focus_mut() = Focus::Info;
             self.get_info_counter_mut().reset();
             self.open_input_mode();
-            return;
+            return Ok(());
+        }
+        if self.is_shared_entry() {
+            return self.copy_shared_entry();
         }
         // otherwise, either open the input mode or perform the action
         match self.get_focus() {
             Focus::List => self.on_right(action),
             Focus::Info => self.open_input_mode(),
         }
+        Ok(())
     }
 
     fn toggle_focus(&mut self) {
@@ -402,14 +518,14 @@ pub trait DoubleColumnMenu<T: Serialize + for<'a> Deserialize<'a> + PartialEq +
     }
 
     fn open_remove_mode(&mut self) {
-        if self.is_new_entry() {
+        if self.is_new_entry() || self.is_shared_entry() {
             return;
         }
         *self.get_input_mode_mut() = InputMode::Remove;
     }
 
     fn open_input_mode(&mut self) {
-        if self.get_focus() == &Focus::List {
+        if self.get_focus() == &Focus::List || self.is_shared_entry() {
             return
         };
         let buffer = self.get_input_buffer();
@@ -417,10 +533,17 @@ pub trait DoubleColumnMenu<T: Serialize + for<'a> Deserialize<'a> + PartialEq +
         *self.get_input_mode_mut() = InputMode::Editing;
     }
 
-    fn close_input_mode(&mut self) {
+    /// Save the edited value. It is kept if it is invalid, but the error
+    /// is reported such that it can be corrected.
+    fn close_input_mode(&mut self) -> Result<()> {
         let buffer = self.get_text_area().lines().join("\n");
-        self.set_input_buffer(&buffer);
         *self.get_input_mode_mut() = InputMode::Normal;
+        self.set_input_buffer(&buffer)?;
+        let index = self.get_info_counter().get_value() as usize;
+        match self.get_display_errors().into_iter().find(|(field, _)| *field == index) {
+            Some((_, message)) => Err(eyre!(message)),
+            None => Ok(()),
+        }
     }
      
 }
@@ -1,3 +1,29 @@
+use color_eyre::eyre::{eyre, Result};
+use serde::Serialize;
+use crate::double_column_menu::toml_list::TomlList;
+
+/// Parse a yes/no value from the editor.
+pub fn parse_bool(value: &str) -> bool {
+    matches!(value.trim().to_lowercase().as_str(), "yes" | "y" | "true" | "1")
+}
+
+/// Format a boolean value for the editor.
+pub fn format_bool(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+/// Make a name unique among the existing names: if it is taken, the
+/// smallest integer `i` is appended as `name(i)` such that it is free.
+pub fn unique_name(name: &str, existing: &[String]) -> String {
+    let mut new_name = name.to_string();
+    let mut i = 1;
+    while existing.contains(&new_name) {
+        new_name = format!("{}({})", name, i);
+        i += 1;
+    }
+    new_name
+}
+
 pub trait Entry {
     fn get_entry_name(&self) -> String;
     fn set_entry_name(&mut self, name: &str);
@@ -5,4 +31,105 @@ pub trait Entry {
     fn set_value_from_index(&mut self, index: usize, value: &str);
     fn get_entry_names(&self) -> Vec<String>;
     fn get_entry_values(&self) -> Vec<String>;
+
+    /// Additional read-only lines shown below the fields in the info pane.
+    fn get_info_text(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The invalid fields as index in the info pane and error message.
+    fn get_field_errors(&self) -> Vec<(usize, String)> {
+        Vec::new()
+    }
+
+    /// Update references to another entry of the list that was renamed.
+    fn rename_references(&mut self, _old_name: &str, _new_name: &str) {}
+}
+
+/// A single change of the entries from the menu. It is applied to the
+/// latest version of the file, such that the changes of other instances
+/// are kept.
+#[derive(Debug, PartialEq)]
+pub enum EntryChange<T> {
+    /// Add the entry, with a name that is unique in the file.
+    Add(T),
+    /// Set the value of a field of the named entry.
+    Set { name: String, index: usize, value: String },
+    /// Remove the named entry.
+    Remove(String),
+}
+
+impl<T: Entry + Serialize> EntryChange<T> {
+    /// Apply the change and return the name of the changed entry.
+    pub fn apply(self, entries: &mut TomlList<T>) -> Result<String> {
+        let names_except = |entries: &TomlList<T>, name: &str| -> Vec<String> {
+            entries.entry.iter().map(|e| e.get_entry_name())
+                .filter(|n| n != name).collect()
+        };
+        match self {
+            EntryChange::Add(mut entry) => {
+                let name = unique_name(&entry.get_entry_name(), &names_except(entries, ""));
+                entry.set_entry_name(&name);
+                entries.push(entry);
+                Ok(name)
+            },
+            EntryChange::Set { name, index, value } => {
+                let others = names_except(entries, &name);
+                let entry = entries.entry.iter_mut()
+                    .find(|e| e.get_entry_name() == name)
+                    .ok_or_else(|| eyre!("'{}' was removed in the meantime.", name))?;
+                let value = if index == 0 { unique_name(&value, &others) } else { value };
+                entry.set_value_from_index(index, &value);
+                let new_name = entry.get_entry_name();
+                if new_name != name {
+                    for entry in entries.entry.iter_mut() {
+                        entry.rename_references(&name, &new_name);
+                    }
+                }
+                Ok(new_name)
+            },
+            EntryChange::Remove(name) => {
+                entries.entry.retain(|e| e.get_entry_name() != name);
+                Ok(name)
+            },
+        }
+    }
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::menus::cluster::Cluster;
+
+    #[test]
+    fn test_changes_keep_other_instances() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut list = TomlList::new();
+        list.push(Cluster::new("levante", "levante.dkrz.de", "u", ""));
+        list.save_to(dir.path(), "clusters").unwrap();
+        let modify = |change: EntryChange<Cluster>| {
+            TomlList::modify_in(dir.path(), "clusters", |entries| change.apply(entries))
+                .unwrap()
+        };
+        // two instances add an entry with the same name
+        assert_eq!(modify(EntryChange::Add(Cluster::new("mistral", "", "", ""))).unwrap(),
+                   "mistral");
+        assert_eq!(modify(EntryChange::Add(Cluster::new("mistral", "", "", ""))).unwrap(),
+                   "mistral(1)");
+        let set = |name: &str, index: usize, value: &str| EntryChange::Set {
+            name: name.to_string(), index, value: value.to_string() };
+        assert_eq!(modify(set("levante", 2, "ab1234")).unwrap(), "levante");
+        assert_eq!(modify(set("mistral(1)", 0, "levante")).unwrap(), "levante(1)");
+        assert_eq!(modify(EntryChange::Remove("mistral".to_string())).unwrap(), "mistral");
+        assert!(modify(set("mistral", 1, "host")).is_err());
+
+        let loaded: TomlList<Cluster> = TomlList::load_from(dir.path(), "clusters").unwrap();
+        let names: Vec<String> = loaded.entry.iter().map(|c| c.name.clone()).collect();
+        assert_eq!(names, vec!["levante", "levante(1)"]);
+        assert_eq!(loaded.get(0).unwrap().user, "ab1234");
+    }
 }
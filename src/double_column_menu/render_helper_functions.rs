@@ -22,9 +22,9 @@ pub fn render_border(f: &mut Frame, area: &Rect, title: &str,
     block.inner(*area)
 }
 
-pub fn render_list(f: &mut Frame, area: &Rect, items: Vec<String>,
-                   enable_highlight: bool, counter: usize, 
-                   highlight_symbol: &str) {
+pub fn render_list<'a>(f: &mut Frame, area: &Rect, items: Vec<impl Into<ListItem<'a>>>,
+                       enable_highlight: bool, counter: usize,
+                       highlight_symbol: &str) {
     let highlight_style = match enable_highlight {
         true => Style::default().add_modifier(Modifier::BOLD)
             .bg(Color::Blue).fg(Color::Black),
@@ -72,10 +72,45 @@ pub fn render_info_dialog(f: &mut Frame, text: &str, color: Color, vsize: u16) {
         .block(Block::default().borders(Borders::ALL)
                .border_type(BorderType::Rounded))
         .style(Style::default().fg(color))
-        .alignment(Alignment::Center),
+        .alignment(Alignment::Center)
+        .wrap(Wrap { trim: true }),
         rect);
 }
 
+/// Render a large dialog with lines of a log. `scroll` is the number of
+/// lines scrolled up from the bottom, such that new lines stay visible.
+pub fn render_log_dialog(f: &mut Frame, title: &str, footer: &str,
+                         lines: Vec<String>, scroll: usize, color: Color) {
+    let width = (0.8 * (f.area().width as f32)) as u16;
+    let height = (0.8 * (f.area().height as f32)) as u16;
+    let rect = centered_rect(f.area(), width, height);
+    f.render_widget(Clear, rect); //this clears out the background
+    let block = Block::default().title(title).borders(Borders::ALL)
+        .border_type(BorderType::Rounded)
+        .title_bottom(Line::from(footer).alignment(Alignment::Right))
+        .border_style(Style::default().fg(color));
+    let inner_height = block.inner(rect).height as usize;
+    let end = lines.len().saturating_sub(scroll);
+    let start = end.saturating_sub(inner_height);
+    let text: Vec<Line> = lines[start..end].iter()
+        .map(|line| Line::from(line.as_str()))
+        .collect();
+    f.render_widget(
+        Paragraph::new(text).block(block),
+        rect);
+}
+
+pub fn render_select_dialog(f: &mut Frame, title: &str, items: Vec<String>,
+                            counter: usize) {
+    let window_width = f.area().width;
+    let text_area_width = (0.8 * (window_width as f32)) as u16;
+
+    let rect = centered_rect(f.area(), text_area_width, items.len() as u16 + 2);
+    f.render_widget(Clear, rect); //this clears out the background
+    let inner_area = render_border(f, &rect, title, true);
+    render_list(f, &inner_area, items, true, counter, " > ");
+}
+
 pub fn horizontal_split(area: &Rect, percentage: u16) -> Vec<Rect> {
     let layout = Layout::default()
         .direction(Direction::Horizontal)
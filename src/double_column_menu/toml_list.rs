@@ -1,9 +1,55 @@
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
 use color_eyre::eyre;
 use serde::{Serialize, Deserialize};
+use toml::value::{Table, Value};
+use crate::config::config_dir;
+
+/// A migration upgrades a single entry of a file by one version.
+pub type Migration = fn(&mut Table);
+
+/// Entries that are stored in a versioned file.
+///
+/// The migration at index `i` upgrades an entry from version `i` to
+/// version `i + 1`, files without a version field have version 0.
+/// New fields are added by appending a migration that inserts them.
+pub trait Migrate {
+    fn migrations() -> Vec<Migration>;
+
+    /// The version of the files written by this program.
+    fn current_version() -> u32 {
+        Self::migrations().len() as u32
+    }
+}
+
+/// Insert a value into the entry if the key is missing.
+pub fn insert_default(entry: &mut Table, key: &str, value: Value) {
+    entry.entry(key.to_string()).or_insert(value);
+}
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
 pub struct TomlList<T> {
+    /// Values that the entries inherit if they do not set them, e.g. the
+    /// per-cluster defaults of the spawner presets.
+    #[serde(default, skip_serializing_if = "Table::is_empty")]
+    pub defaults: Table,
     pub entry: Vec<T>,
+    /// The version of the file if it was upgraded while loading.
+    #[serde(skip)]
+    pub migrated_from: Option<u32>,
+}
+
+/// The layout of the file on disk.
+#[derive(Serialize)]
+struct TomlFile<'a, T> {
+    version: u32,
+    #[serde(skip_serializing_if = "Table::is_empty")]
+    defaults: &'a Table,
+    entry: &'a Vec<T>,
 }
 
 impl<T: Serialize> TomlList<T> {
@@ -12,7 +58,9 @@ impl<T: Serialize> TomlList<T> {
     // =======================================================================
     pub fn new() -> TomlList<T> {
         TomlList {
+            defaults: Table::new(),
             entry: Vec::new(),
+            migrated_from: None,
         }
     }
 
@@ -44,35 +92,246 @@ impl<T: Serialize> TomlList<T> {
         self.entry.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.entry.is_empty()
+    }
+
+}
+
+impl<T: Serialize + Migrate> TomlList<T> {
     // =======================================================================
     //            FILE OPERATIONS
     // =======================================================================
 
+    /// Save the list to `<filename>.toml` in the configuration directory.
     pub fn save(&self, filename: &str) -> eyre::Result<()> {
-        let home = std::env::var("HOME")?;
-        let config_dir = format!("{}/.config/code-remote", home);
-        std::fs::create_dir_all(&config_dir)?;
-        let file = format!("{}/{}.toml", config_dir, filename);
-        let toml_str = toml::to_string(&self)?;
-        // write the toml string to the file
-        // if the file exists, it should be overwritten
-        std::fs::write(file, toml_str)?;
+        self.save_to(&config_dir()?, filename)
+    }
+
+    pub fn save_to(&self, dir: &Path, filename: &str) -> eyre::Result<()> {
+        let file = prepare_file(dir, filename)?;
+        let _lock = FileLock::exclusive(&file)?;
+        self.write_file(&file)
+    }
+
+    /// Load the list, modify it and save it again while holding the lock,
+    /// such that concurrent instances do not overwrite each other.
+    pub fn modify<R>(filename: &str, f: impl FnOnce(&mut TomlList<T>) -> R) -> eyre::Result<R>
+    where for<'de> T: Deserialize<'de> {
+        Self::modify_in(&config_dir()?, filename, f)
+    }
+
+    pub fn modify_in<R>(dir: &Path, filename: &str,
+                        f: impl FnOnce(&mut TomlList<T>) -> R) -> eyre::Result<R>
+    where for<'de> T: Deserialize<'de> {
+        let file = prepare_file(dir, filename)?;
+        let _lock = FileLock::exclusive(&file)?;
+        let mut list = Self::read_file(&file)?;
+        if let Some(version) = list.migrated_from {
+            backup_before_migration(&file, version)?;
+        }
+        let result = f(&mut list);
+        list.write_file(&file)?;
+        Ok(result)
+    }
+
+    /// Remove `<filename>.toml` from the configuration directory. The
+    /// last version is kept as `<filename>.toml.bak`.
+    pub fn remove(filename: &str) -> eyre::Result<()> {
+        Self::remove_from(&config_dir()?, filename)
+    }
+
+    pub fn remove_from(dir: &Path, filename: &str) -> eyre::Result<()> {
+        let file = dir.join(format!("{}.toml", filename));
+        if !file.exists() {
+            return Ok(());
+        }
+        let _lock = FileLock::exclusive(&file)?;
+        std::fs::rename(&file, with_suffix(&file, "bak"))?;
         Ok(())
     }
 
+    /// Load the list from `<filename>.toml` in the configuration directory.
     pub fn load(filename: &str) -> eyre::Result<TomlList<T>> 
     where for<'de> T: Deserialize<'de> {
-        let home = std::env::var("HOME")?;
-        let file = format!("{}/.config/code-remote/{}.toml", home, filename);
+        Self::load_from(&config_dir()?, filename)
+    }
+
+    pub fn load_from(dir: &Path, filename: &str) -> eyre::Result<TomlList<T>> 
+    where for<'de> T: Deserialize<'de> {
+        let file = dir.join(format!("{}.toml", filename));
         // if the file does not exist, return an empty list
-        if !std::path::Path::new(&file).exists() {
+        if !file.exists() {
+            return Ok(TomlList::new());
+        }
+        {
+            let _lock = FileLock::shared(&file)?;
+            let list = Self::read_file(&file)?;
+            if list.migrated_from.is_none() {
+                return Ok(list);
+            }
+        }
+        // The file is outdated: upgrade it on disk. Another instance might
+        // have done this in the meantime, so read it again under the lock.
+        let _lock = FileLock::exclusive(&file)?;
+        let list = Self::read_file(&file)?;
+        if let Some(version) = list.migrated_from {
+            backup_before_migration(&file, version)?;
+            list.write_file(&file)?;
+        }
+        Ok(list)
+    }
+
+    /// Load a list that is not owned by code-remote, e.g. from a shared
+    /// directory. It is upgraded in memory only and never written.
+    pub fn load_read_only(file: &Path) -> eyre::Result<TomlList<T>>
+    where for<'de> T: Deserialize<'de> {
+        Self::read_file(file)
+    }
+
+    /// Read the list and upgrade the entries to the current version.
+    fn read_file(file: &Path) -> eyre::Result<TomlList<T>>
+    where for<'de> T: Deserialize<'de> {
+        if !file.exists() {
             return Ok(TomlList::new());
         }
-        // otherwise, load the list
         let toml_str = std::fs::read_to_string(file)?;
-        let list: TomlList<T> = toml::from_str(&toml_str)?;
+        let mut table: Table = toml::from_str(&toml_str)?;
+        let version = migrate_table::<T>(&mut table)
+            .map_err(|e| eyre::eyre!("{}: {}", file.display(), e))?;
+        let mut list: TomlList<T> = Value::Table(table).try_into()?;
+        if version < T::current_version() {
+            list.migrated_from = Some(version);
+        }
         Ok(list)
     }
+
+    /// Describe the migration that ran while loading `<filename>.toml`.
+    pub fn migration_message(&self, filename: &str) -> Option<String> {
+        self.migrated_from.map(|version| format!(
+            "Upgraded {0}.toml from version {1} to version {2}. \
+             The old file was saved as {0}.toml.v{1}.bak",
+            filename, version, T::current_version()))
+    }
+
+    /// Write the list to a temporary file and rename it afterwards, such
+    /// that a crash never leaves a truncated file behind. The previous
+    /// version of the file is kept as `<file>.bak`.
+    fn write_file(&self, file: &Path) -> eyre::Result<()> {
+        let toml_file = TomlFile {
+            version: T::current_version(),
+            defaults: &self.defaults,
+            entry: &self.entry,
+        };
+        let toml_str = toml::to_string(&toml_file)?;
+        let tmp_file = with_suffix(file, "tmp");
+        {
+            let mut tmp = File::create(&tmp_file)?;
+            tmp.write_all(toml_str.as_bytes())?;
+            tmp.sync_all()?;
+        }
+        if file.exists() {
+            std::fs::copy(file, with_suffix(file, "bak"))?;
+        }
+        std::fs::rename(&tmp_file, file)?;
+        Ok(())
+    }
+}
+
+/// Upgrade all entries of the table to the current version and return
+/// the version the table had before.
+fn migrate_table<T: Migrate>(table: &mut Table) -> eyre::Result<u32> {
+    let version = read_version::<T>(table, "version")?;
+    migrate_entries::<T>(table.get_mut("entry"), version);
+    table.remove("version");
+    Ok(version)
+}
+
+/// Read the version of entries of type `T` from the table. A missing
+/// version is 0, versions newer than this program supports are an error.
+pub fn read_version<T: Migrate>(table: &Table, key: &str) -> eyre::Result<u32> {
+    let version = match table.get(key) {
+        None => 0,
+        Some(Value::Integer(v)) if *v >= 0 => *v as u32,
+        Some(v) => eyre::bail!("invalid version {}", v),
+    };
+    if version > T::current_version() {
+        eyre::bail!("the file has version {} but this code-remote only supports \
+                     up to version {}, please update code-remote",
+                    version, T::current_version());
+    }
+    Ok(version)
+}
+
+/// Upgrade an array of entries from the given version to the current one.
+pub fn migrate_entries<T: Migrate>(entries: Option<&mut Value>, version: u32) {
+    let migrations = T::migrations();
+    if let Some(Value::Array(entries)) = entries {
+        for entry in entries.iter_mut() {
+            if let Value::Table(entry) = entry {
+                for migration in &migrations[version as usize..] {
+                    migration(entry);
+                }
+            }
+        }
+    }
+}
+
+/// Keep a copy of an outdated file as `<file>.v<version>.bak`. Unlike the
+/// regular backup it is not overwritten by later saves.
+fn backup_before_migration(file: &Path, version: u32) -> eyre::Result<()> {
+    std::fs::copy(file, with_suffix(file, &format!("v{}.bak", version)))?;
+    Ok(())
+}
+
+/// Create the directory of the file and return the path of the file.
+fn prepare_file(dir: &Path, filename: &str) -> eyre::Result<PathBuf> {
+    let file = dir.join(format!("{}.toml", filename));
+    if let Some(parent) = file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(file)
+}
+
+/// Append a suffix to the file name, e.g. `clusters.toml.bak`.
+pub fn with_suffix(file: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(file.as_os_str());
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Advisory lock on `<file>.lock`, released when dropped.
+struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    fn open(file: &Path) -> eyre::Result<File> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(with_suffix(file, "lock"))?)
+    }
+
+    fn exclusive(file: &Path) -> eyre::Result<FileLock> {
+        let file = Self::open(file)?;
+        file.lock()?;
+        Ok(FileLock { file })
+    }
+
+    fn shared(file: &Path) -> eyre::Result<FileLock> {
+        let file = Self::open(file)?;
+        file.lock_shared()?;
+        Ok(FileLock { file })
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
 }
 
 #[cfg(test)]
@@ -112,10 +371,9 @@ mod tests {
         let mut list: TomlList<cluster::Cluster> = TomlList::new();
         let cluster = cluster::Cluster::new("test", "test", "test", "test");
         list.push(cluster);
-        list.save("test").unwrap();
-        let home = std::env::var("HOME").unwrap();
-        let file = format!("{}/.config/code-remote/test.toml", home);
-        assert!(std::path::Path::new(&file).exists());
+        let dir = tempfile::tempdir().unwrap();
+        list.save_to(dir.path(), "test").unwrap();
+        assert!(dir.path().join("test.toml").exists());
     }
 
     #[test]
@@ -126,10 +384,12 @@ mod tests {
             "mname", "mhost", "muser", "mid");
         list.push(cluster);
         // Save the list
-        list.save("test").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        list.save_to(dir.path(), "test").unwrap();
 
         // Load the list
-        let loaded_list: TomlList<cluster::Cluster> = TomlList::load("test").unwrap();
+        let loaded_list: TomlList<cluster::Cluster> =
+            TomlList::load_from(dir.path(), "test").unwrap();
 
         // Test the loaded list
         assert_eq!(loaded_list.len(), 1);
@@ -139,4 +399,116 @@ mod tests {
         assert_eq!(entry.user, "muser");
         assert_eq!(entry.identity_file, "mid");
     }
+
+    #[test]
+    fn test_save_keeps_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut list: TomlList<cluster::Cluster> = TomlList::new();
+        list.push(cluster::Cluster::new("first", "h", "u", ""));
+        list.save_to(dir.path(), "test").unwrap();
+        list.push(cluster::Cluster::new("second", "h", "u", ""));
+        list.save_to(dir.path(), "test").unwrap();
+
+        let backup = std::fs::read_to_string(dir.path().join("test.toml.bak")).unwrap();
+        let backup: TomlList<cluster::Cluster> = toml::from_str(&backup).unwrap();
+        assert_eq!(backup.len(), 1);
+        assert!(!dir.path().join("test.toml.tmp").exists());
+        let loaded: TomlList<cluster::Cluster> =
+            TomlList::load_from(dir.path(), "test").unwrap();
+        assert_eq!(loaded.len(), 2);
+    }
+
+    #[test]
+    fn test_concurrent_modify() {
+        let dir = tempfile::tempdir().unwrap();
+        let handles: Vec<_> = (0..4).map(|i| {
+            let dir = dir.path().to_path_buf();
+            std::thread::spawn(move || {
+                for j in 0..5 {
+                    TomlList::<cluster::Cluster>::modify_in(&dir, "test", |list| {
+                        let name = format!("{}-{}", i, j);
+                        list.push(cluster::Cluster::new(&name, "h", "u", ""));
+                    }).unwrap();
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let loaded: TomlList<cluster::Cluster> =
+            TomlList::load_from(dir.path(), "test").unwrap();
+        assert_eq!(loaded.len(), 20);
+    }
+
+    #[test]
+    fn test_save_writes_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut list: TomlList<cluster::Cluster> = TomlList::new();
+        list.push(cluster::Cluster::new("test", "h", "u", ""));
+        list.save_to(dir.path(), "test").unwrap();
+        let content = std::fs::read_to_string(dir.path().join("test.toml")).unwrap();
+        let table: Table = toml::from_str(&content).unwrap();
+        assert_eq!(table["version"].as_integer(),
+                   Some(cluster::Cluster::current_version() as i64));
+        let loaded: TomlList<cluster::Cluster> =
+            TomlList::load_from(dir.path(), "test").unwrap();
+        assert_eq!(loaded.migrated_from, None);
+    }
+
+    #[test]
+    fn test_load_migrates_old_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = "[[entry]]\nname = \"levante\"\nhost = \"h\"\n\
+                   user = \"u\"\nidentity_file = \"\"\n";
+        std::fs::write(dir.path().join("test.toml"), old).unwrap();
+
+        let loaded: TomlList<cluster::Cluster> =
+            TomlList::load_from(dir.path(), "test").unwrap();
+        assert_eq!(loaded.migrated_from, Some(0));
+        assert_eq!(loaded.get(0).unwrap().name, "levante");
+        assert!(!loaded.get(0).unwrap().preseed_vscode_server);
+        assert!(loaded.migration_message("test").unwrap().contains("test.toml.v0.bak"));
+
+        // the old file is kept and the new one is upgraded
+        let backup = std::fs::read_to_string(dir.path().join("test.toml.v0.bak")).unwrap();
+        assert_eq!(backup, old);
+        let reloaded: TomlList<cluster::Cluster> =
+            TomlList::load_from(dir.path(), "test").unwrap();
+        assert_eq!(reloaded.migrated_from, None);
+        assert_eq!(reloaded.entry, loaded.entry);
+    }
+
+    #[test]
+    fn test_save_load_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut list: TomlList<cluster::Cluster> = TomlList::new();
+        list.defaults.insert("user".to_string(), "me".into());
+        list.push(cluster::Cluster::new("test", "h", "u", ""));
+        list.save_to(dir.path(), "test").unwrap();
+        let loaded: TomlList<cluster::Cluster> =
+            TomlList::load_from(dir.path(), "test").unwrap();
+        assert_eq!(loaded, list);
+    }
+
+    #[test]
+    fn test_remove_keeps_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut list: TomlList<cluster::Cluster> = TomlList::new();
+        list.push(cluster::Cluster::new("test", "h", "u", ""));
+        list.save_to(dir.path(), "sub/test").unwrap();
+        TomlList::<cluster::Cluster>::remove_from(dir.path(), "sub/test").unwrap();
+        assert!(!dir.path().join("sub/test.toml").exists());
+        assert!(dir.path().join("sub/test.toml.bak").exists());
+        // removing a missing file is not an error
+        TomlList::<cluster::Cluster>::remove_from(dir.path(), "sub/test").unwrap();
+    }
+
+    #[test]
+    fn test_load_rejects_newer_version() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("test.toml"), "version = 999\n").unwrap();
+        let result: eyre::Result<TomlList<cluster::Cluster>> =
+            TomlList::load_from(dir.path(), "test");
+        assert!(result.unwrap_err().to_string().contains("update code-remote"));
+    }
 }
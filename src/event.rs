@@ -4,12 +4,14 @@ use std::{
     time::{Duration, Instant},
 };
 
-use color_eyre::Result;
+use color_eyre::{Result, eyre::eyre};
 use crossterm::event::{self, Event as CrosstermEvent, KeyEvent, MouseEvent};
 
+use crate::progress::TaskEvent;
+
 
 /// Terminal events.
-#[derive(Clone, Copy, Debug)]
+#[derive(Debug)]
 pub enum Event {
     /// Terminal tick.
     Tick,
@@ -19,18 +21,18 @@ pub enum Event {
     Mouse(MouseEvent),
     /// Terminal resize.
     Resize(u16, u16),
+    /// Progress or result of the background task with the given id.
+    Task(u64, TaskEvent),
 }
 
 /// Terminal event handler.
 #[derive(Debug)]
 pub struct EventHandler {
     /// Event sender channel.
-    #[allow(dead_code)]
     sender: mpsc::Sender<Event>,
     /// Event receiver channel.
     receiver: mpsc::Receiver<Event>,
     /// Event handler thread.
-    #[allow(dead_code)]
     handler: thread::JoinHandle<()>,
 }
 
@@ -48,9 +50,17 @@ impl EventHandler {
                         .checked_sub(last_tick.elapsed())
                         .unwrap_or(tick_rate);
 
+                    // the loop ends if the terminal can not be read or the
+                    // receiver is gone, next() fails in that case
                     // ANCHOR: eventhandler_poll
-                    if event::poll(timeout).expect("unable to poll for event") {
-                        match event::read().expect("unable to read event") {
+                    let Ok(ready) = event::poll(timeout) else {
+                        return;
+                    };
+                    if ready {
+                        let Ok(event) = event::read() else {
+                            return;
+                        };
+                        let sent = match event {
                             CrosstermEvent::Key(e) => {
                                 if e.kind == event::KeyEventKind::Press {
                                     sender.send(Event::Key(e))
@@ -64,16 +74,19 @@ impl EventHandler {
                             CrosstermEvent::Resize(w, h) => {
                                 sender.send(Event::Resize(w, h))
                             }
-                            _ => unimplemented!(),
+                            // focus changes and pasted text are not used
+                            _ => Ok(()),
+                        };
+                        if sent.is_err() {
+                            return;
                         }
-                        .expect("failed to send terminal event")
                     }
                     // ANCHOR_END: eventhandler_poll
 
                     if last_tick.elapsed() >= tick_rate {
-                        sender
-                            .send(Event::Tick)
-                            .expect("failed to send tick event");
+                        if sender.send(Event::Tick).is_err() {
+                            return;
+                        }
                         last_tick = Instant::now();
                     }
                 }
@@ -86,11 +99,26 @@ impl EventHandler {
         }
     }
 
+    /// Get a sender such that background tasks can send events.
+    pub fn sender(&self) -> mpsc::Sender<Event> {
+        self.sender.clone()
+    }
+
     /// Receive the next event from the handler thread.
     ///
     /// This function will always block the current thread if
     /// there is no data available and it's possible for more data to be sent.
     pub fn next(&self) -> Result<Event> {
-        Ok(self.receiver.recv()?)
+        loop {
+            match self.receiver.recv_timeout(Duration::from_secs(1)) {
+                Ok(event) => return Ok(event),
+                // the handler thread stops if the terminal can not be read
+                Err(mpsc::RecvTimeoutError::Timeout) if self.handler.is_finished() => {
+                    return Err(eyre!("Lost the connection to the terminal"));
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => {},
+                Err(e) => return Err(e.into()),
+            }
+        }
     }
 }
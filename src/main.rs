@@ -1,8 +1,10 @@
+use clap::Parser;
 use color_eyre::eyre::Result;
 use ratatui::{backend::CrosstermBackend, Terminal};
 
 use crate::{
     app::App,
+    cli::Cli,
     event::{Event, EventHandler},
     tui::Tui,
     update::update};
@@ -14,10 +16,26 @@ pub mod event;
 pub mod ui;
 pub mod tui;
 pub mod update;
+pub mod cli;
+pub mod config;
+pub mod progress;
+pub mod run_log;
+pub mod paths;
 
 
 
 fn main() -> Result<()> {
+    let cli = Cli::parse();
+    if let Some(config) = &cli.config {
+        config::set_config_dir(config);
+    }
+    match cli.command {
+        Some(command) => std::process::exit(cli::run(command, cli.json)),
+        None => run_tui(),
+    }
+}
+
+fn run_tui() -> Result<()> {
     // Create an application.
     let mut app = App::new()?;
 
@@ -27,23 +45,30 @@ fn main() -> Result<()> {
     let events = EventHandler::new(250);
     let mut tui = Tui::new(terminal, events);
     tui.enter()?;
+    app.sender = Some(tui.events.sender());
+
+    // Start the main loop. The terminal is restored even if it fails.
+    let result = run_loop(&mut app, &mut tui);
+
+    // Exit the user interface.
+    tui.exit()?;
+
+    result
+}
 
-    // Start the main loop.
+fn run_loop(app: &mut App, tui: &mut Tui) -> Result<()> {
     while !app.should_quit {
         // Render the user interface.
-        tui.draw(&mut app)?;
+        tui.draw(app)?;
         // Handle events.
         match tui.events.next()? {
-            Event::Tick => {}
-            Event::Key(key_event) => update(&mut app, key_event),
+            Event::Tick => app.tick(),
+            Event::Key(key_event) => update(app, key_event),
             Event::Mouse(_) => {}
             Event::Resize(_, _) => {}
+            Event::Task(id, event) => app.handle_task_event(id, event),
         };
     }
-
-    // Exit the user interface.
-    tui.exit()?;
-
     Ok(())
 }
 
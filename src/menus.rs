@@ -2,3 +2,8 @@ pub mod cluster;
 pub mod spawner;
 pub mod cluster_menu;
 pub mod spawner_menu;
+pub mod vscode_server;
+pub mod job;
+pub mod bundle;
+pub mod connection_error;
+pub mod ssh_keys;
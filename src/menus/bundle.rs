@@ -0,0 +1,401 @@
+use std::{collections::HashMap, fs::File, io::Write, path::Path};
+use color_eyre::eyre::{bail, eyre, Result};
+use serde::{Serialize, Deserialize};
+use toml::value::{Table, Value};
+
+use crate::config::config_dir;
+use crate::double_column_menu::{
+    entry::{Entry, unique_name},
+    toml_list::{Migrate, TomlList, migrate_entries, read_version, with_suffix}};
+use crate::menus::{
+    cluster::{Cluster, new_cluster_id},
+    cluster_menu::CLUSTER_FILE,
+    spawner::{Spawner, is_unset}};
+
+/// A standalone file with clusters and their presets, used to share
+/// configurations with other users.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bundle {
+    pub cluster_version: u32,
+    pub preset_version: u32,
+    pub cluster: Vec<BundleCluster>,
+}
+
+/// A cluster with the defaults and presets of its preset file.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct BundleCluster {
+    #[serde(flatten)]
+    pub cluster: Cluster,
+    #[serde(default, skip_serializing_if = "Table::is_empty")]
+    pub defaults: Table,
+    #[serde(default)]
+    pub preset: Vec<Spawner>,
+}
+
+/// What to do with a cluster of the bundle whose name already exists.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Conflict {
+    /// Add the cluster under a new name, e.g. `levante(1)`.
+    Rename,
+    /// Add the presets to the existing cluster.
+    Merge,
+    /// Do not import the cluster.
+    Skip,
+}
+
+/// What happened to a cluster of the bundle.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportAction {
+    Added,
+    Renamed,
+    Merged,
+    Skipped,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ImportedCluster {
+    /// The name of the cluster in the bundle.
+    pub bundle_name: String,
+    /// The name of the cluster after the import.
+    pub name: String,
+    pub action: ImportAction,
+    /// The number of imported presets.
+    pub presets: usize,
+}
+
+impl ImportedCluster {
+    /// Short description for the summary after the import.
+    pub fn format_line(&self) -> String {
+        match self.action {
+            ImportAction::Added => format!("{} ({} sessions)", self.name, self.presets),
+            ImportAction::Renamed => format!(
+                "{} as {} ({} sessions)", self.bundle_name, self.name, self.presets),
+            ImportAction::Merged => format!(
+                "{} sessions into {}", self.presets, self.name),
+            ImportAction::Skipped => format!("{} skipped", self.bundle_name),
+        }
+    }
+}
+
+impl Bundle {
+    // =======================================================================
+    //             EXPORT
+    // =======================================================================
+
+    /// Collect the clusters and their presets. The job ids are never
+    /// exported, `strip_user` also removes the user and identity file.
+    pub fn create(clusters: Vec<Cluster>, strip_user: bool) -> Result<Bundle> {
+        Self::create_from(&config_dir()?, clusters, strip_user)
+    }
+
+    pub fn create_from(dir: &Path, clusters: Vec<Cluster>, strip_user: bool) -> Result<Bundle> {
+        let cluster = clusters.into_iter().map(|mut cluster| {
+            let presets: TomlList<Spawner> = TomlList::load_from(dir, &cluster.preset_file())?;
+            if strip_user {
+                cluster.user.clear();
+                cluster.identity_file.clear();
+                cluster.certificate_file.clear();
+            }
+            let preset = presets.entry.into_iter()
+                .map(|preset| Spawner { job_id: String::new(), ..preset })
+                .collect();
+            Ok(BundleCluster { cluster, defaults: presets.defaults, preset })
+        }).collect::<Result<_>>()?;
+        Ok(Bundle {
+            cluster_version: Cluster::current_version(),
+            preset_version: Spawner::current_version(),
+            cluster,
+        })
+    }
+
+    /// Write the bundle to a file. An existing file is only replaced if
+    /// `overwrite` is set.
+    pub fn write(&self, file: &Path, overwrite: bool) -> Result<()> {
+        if file.exists() && !overwrite {
+            bail!("{} already exists", file.display());
+        }
+        let tmp_file = with_suffix(file, "tmp");
+        {
+            let mut tmp = File::create(&tmp_file)?;
+            tmp.write_all(toml::to_string(self)?.as_bytes())?;
+            tmp.sync_all()?;
+        }
+        std::fs::rename(&tmp_file, file)?;
+        Ok(())
+    }
+
+    // =======================================================================
+    //             IMPORT
+    // =======================================================================
+
+    /// Read a bundle and upgrade it if it was written by an older version.
+    pub fn read(file: &Path) -> Result<Bundle> {
+        let content = std::fs::read_to_string(file)?;
+        Self::parse(&content).map_err(|e| eyre!("{}: {}", file.display(), e))
+    }
+
+    pub fn parse(content: &str) -> Result<Bundle> {
+        let mut table: Table = toml::from_str(content)?;
+        let cluster_version = read_version::<Cluster>(&table, "cluster_version")?;
+        let preset_version = read_version::<Spawner>(&table, "preset_version")?;
+        migrate_entries::<Cluster>(table.get_mut("cluster"), cluster_version);
+        if let Some(Value::Array(clusters)) = table.get_mut("cluster") {
+            for cluster in clusters.iter_mut().filter_map(|c| c.as_table_mut()) {
+                migrate_entries::<Spawner>(cluster.get_mut("preset"), preset_version);
+            }
+        }
+        table.insert("cluster_version".to_string(), Cluster::current_version().into());
+        table.insert("preset_version".to_string(), Spawner::current_version().into());
+        Ok(Value::Table(table).try_into()?)
+    }
+
+    /// The clusters of the bundle whose name exists in `names`.
+    pub fn conflicts(&self, names: &[String]) -> Vec<String> {
+        self.cluster.iter()
+            .map(|c| c.cluster.name.clone())
+            .filter(|name| names.contains(name))
+            .collect()
+    }
+
+    /// Add the clusters and presets of the bundle to the configuration.
+    /// `on_conflict` is asked what to do with clusters whose name exists.
+    pub fn import(self, on_conflict: impl FnMut(&str) -> Result<Conflict>)
+                  -> Result<Vec<ImportedCluster>> {
+        self.import_into(&config_dir()?, on_conflict)
+    }
+
+    pub fn import_into(self, dir: &Path, mut on_conflict: impl FnMut(&str) -> Result<Conflict>)
+                       -> Result<Vec<ImportedCluster>> {
+        let existing: TomlList<Cluster> = TomlList::load_from(dir, CLUSTER_FILE)?;
+        let mut names: Vec<String> = existing.entry.iter()
+            .map(|c| c.get_entry_name()).collect();
+        let mut imported = Vec::new();
+        for bundle_cluster in self.cluster {
+            let BundleCluster { mut cluster, defaults, preset } = bundle_cluster;
+            let bundle_name = cluster.name.clone();
+            let conflict = if names.contains(&bundle_name) {
+                Some(on_conflict(&bundle_name)?)
+            } else {
+                None
+            };
+            let presets = preset.len();
+            let (action, name) = match conflict {
+                Some(Conflict::Skip) => (ImportAction::Skipped, bundle_name.clone()),
+                Some(Conflict::Merge) => {
+                    let target = existing.entry.iter()
+                        .find(|c| c.name == bundle_name)
+                        .ok_or_else(|| eyre!("The bundle contains {} twice", bundle_name))?;
+                    TomlList::<Spawner>::modify_in(dir, &target.preset_file(), |list| {
+                        merge_presets(list, defaults, preset)
+                    })??;
+                    (ImportAction::Merged, bundle_name.clone())
+                },
+                None | Some(Conflict::Rename) => {
+                    let name = unique_name(&bundle_name, &names);
+                    cluster.name = name.clone();
+                    cluster.id = new_cluster_id();
+                    names.push(name.clone());
+                    TomlList::<Spawner>::modify_in(dir, &cluster.preset_file(), |list| {
+                        merge_presets(list, defaults, preset)
+                    })??;
+                    TomlList::<Cluster>::modify_in(dir, CLUSTER_FILE, |list| {
+                        list.push(cluster);
+                    })?;
+                    match conflict {
+                        Some(_) => (ImportAction::Renamed, name),
+                        None => (ImportAction::Added, name),
+                    }
+                },
+            };
+            imported.push(ImportedCluster {
+                name,
+                bundle_name,
+                action,
+                presets: if action == ImportAction::Skipped { 0 } else { presets },
+            });
+        }
+        Ok(imported)
+    }
+}
+
+/// Add presets to a list. Presets whose name exists are renamed, and the
+/// `extends` fields of the added presets follow the new names. Defaults
+/// of the list take precedence over the added ones, so a default that
+/// differs is written into the added presets that inherit it, such that
+/// they resolve to the same values as in the bundle.
+fn merge_presets(list: &mut TomlList<Spawner>, defaults: Table,
+                 presets: Vec<Spawner>) -> Result<()> {
+    let mut differing = Table::new();
+    for (key, value) in defaults {
+        match list.defaults.get(&key) {
+            None => { list.defaults.insert(key, value); },
+            Some(existing) if *existing == value => {},
+            Some(_) => { differing.insert(key, value); },
+        }
+    }
+    let presets = presets.into_iter()
+        .map(|preset| match preset.extends.is_empty() && !differing.is_empty() {
+            true => pin_defaults(preset, &differing),
+            false => Ok(preset),
+        })
+        .collect::<Result<Vec<Spawner>>>()?;
+    let mut names: Vec<String> = list.entry.iter().map(|p| p.get_entry_name()).collect();
+    let mut renamed = HashMap::new();
+    for preset in presets.iter() {
+        let name = unique_name(&preset.preset_name, &names);
+        names.push(name.clone());
+        renamed.insert(preset.preset_name.clone(), name);
+    }
+    for mut preset in presets {
+        preset.preset_name = renamed[&preset.preset_name].clone();
+        if let Some(parent) = renamed.get(&preset.extends) {
+            preset.extends = parent.clone();
+        }
+        list.push(preset);
+    }
+    Ok(())
+}
+
+/// Set the unset fields of a preset to the given defaults.
+fn pin_defaults(preset: Spawner, defaults: &Table) -> Result<Spawner> {
+    let mut table = match Value::try_from(preset)? {
+        Value::Table(table) => table,
+        _ => unreachable!("a preset is always serialized as a table"),
+    };
+    for (key, value) in defaults {
+        if let Some(field) = table.get_mut(key).filter(|field| is_unset(field)) {
+            *field = value.clone();
+        }
+    }
+    Ok(Value::Table(table).try_into()?)
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_config(dir: &Path) -> Cluster {
+        let cluster = Cluster::new("levante", "levante.dkrz.de", "u301533", "~/.ssh/key");
+        let mut presets = TomlList::new();
+        presets.defaults.insert("account".to_string(), "bb1153".into());
+        presets.push(Spawner {
+            job_id: "123".to_string(),
+            ..Spawner::new("cpu", "", "compute", "01:00:00", "", "")
+        });
+        presets.push(Spawner {
+            extends: "cpu".to_string(),
+            ..Spawner::new("gpu", "", "gpu", "", "", "")
+        });
+        presets.save_to(dir, &cluster.preset_file()).unwrap();
+        let mut clusters = TomlList::new();
+        clusters.push(cluster);
+        clusters.save_to(dir, CLUSTER_FILE).unwrap();
+        clusters.entry.remove(0)
+    }
+
+    #[test]
+    fn test_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let cluster = create_config(dir.path());
+        let bundle = Bundle::create_from(dir.path(), vec![cluster], true).unwrap();
+        let content = toml::to_string(&bundle).unwrap();
+        let bundle = Bundle::parse(&content).unwrap();
+        let exported = &bundle.cluster[0];
+        assert_eq!(exported.cluster.host, "levante.dkrz.de");
+        assert!(exported.cluster.user.is_empty());
+        assert!(exported.cluster.identity_file.is_empty());
+        assert_eq!(exported.defaults["account"].as_str(), Some("bb1153"));
+        assert_eq!(exported.preset.len(), 2);
+        assert!(exported.preset[0].job_id.is_empty());
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let cluster = create_config(dir.path());
+        let bundle = Bundle::create_from(dir.path(), vec![cluster], false).unwrap();
+        let file = dir.path().join("levante.toml");
+        bundle.write(&file, false).unwrap();
+        assert_eq!(Bundle::read(&file).unwrap().cluster.len(), 1);
+        assert!(!with_suffix(&file, "tmp").exists());
+
+        // an existing file is only replaced if confirmed
+        std::fs::write(&file, "").unwrap();
+        assert!(bundle.write(&file, false).is_err());
+        assert!(std::fs::read_to_string(&file).unwrap().is_empty());
+        bundle.write(&file, true).unwrap();
+        assert_eq!(Bundle::read(&file).unwrap().cluster.len(), 1);
+    }
+
+    #[test]
+    fn test_import_conflicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let cluster = create_config(dir.path());
+        let bundle = Bundle::create_from(dir.path(), vec![cluster], false).unwrap();
+
+        assert_eq!(bundle.conflicts(&["levante".to_string()]), vec!["levante"]);
+        assert!(bundle.conflicts(&["mistral".to_string()]).is_empty());
+
+        // rename: a second cluster with its own presets
+        let content = toml::to_string(&bundle).unwrap();
+        let imported = Bundle::parse(&content).unwrap()
+            .import_into(dir.path(), |_| Ok(Conflict::Rename)).unwrap();
+        assert_eq!(imported[0].name, "levante(1)");
+        assert_eq!(imported[0].action, ImportAction::Renamed);
+        assert_eq!(imported[0].format_line(), "levante as levante(1) (2 sessions)");
+        let clusters: TomlList<Cluster> = TomlList::load_from(dir.path(), CLUSTER_FILE).unwrap();
+        assert_eq!(clusters.len(), 2);
+        assert_ne!(clusters.get(0).unwrap().id, clusters.get(1).unwrap().id);
+        let presets: TomlList<Spawner> =
+            TomlList::load_from(dir.path(), &clusters.get(1).unwrap().preset_file()).unwrap();
+        assert_eq!(presets.len(), 2);
+
+        // merge: the presets are added to the existing cluster
+        let imported = Bundle::parse(&content).unwrap()
+            .import_into(dir.path(), |_| Ok(Conflict::Merge)).unwrap();
+        assert_eq!(imported[0].action, ImportAction::Merged);
+        let presets: TomlList<Spawner> =
+            TomlList::load_from(dir.path(), &clusters.get(0).unwrap().preset_file()).unwrap();
+        let names: Vec<String> = presets.entry.iter().map(|p| p.get_entry_name()).collect();
+        assert_eq!(names, vec!["cpu", "gpu", "cpu(1)", "gpu(1)"]);
+        assert_eq!(presets.get(3).unwrap().extends, "cpu(1)");
+
+        // skip: nothing changes
+        let imported = Bundle::parse(&content).unwrap()
+            .import_into(dir.path(), |_| Ok(Conflict::Skip)).unwrap();
+        assert_eq!(imported[0].action, ImportAction::Skipped);
+        let clusters: TomlList<Cluster> = TomlList::load_from(dir.path(), CLUSTER_FILE).unwrap();
+        assert_eq!(clusters.len(), 2);
+    }
+
+    #[test]
+    fn test_merge_differing_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let cluster = create_config(dir.path());
+        let bundle = Bundle::create_from(dir.path(), vec![cluster.clone()], false).unwrap();
+
+        let mut presets: TomlList<Spawner> =
+            TomlList::load_from(dir.path(), &cluster.preset_file()).unwrap();
+        presets.defaults.insert("account".to_string(), Value::from("other"));
+        presets.save_to(dir.path(), &cluster.preset_file()).unwrap();
+
+        bundle.import_into(dir.path(), |_| Ok(Conflict::Merge)).unwrap();
+        let presets: TomlList<Spawner> =
+            TomlList::load_from(dir.path(), &cluster.preset_file()).unwrap();
+        let resolved = |index: usize| presets.get(index).unwrap().resolve(&presets).unwrap();
+        // the existing presets keep the defaults of the list
+        assert_eq!(resolved(0).account, "other");
+        assert_eq!(resolved(1).account, "other");
+        // the merged presets resolve to the same values as in the bundle
+        assert_eq!(presets.get(2).unwrap().account, "bb1153");
+        assert_eq!(resolved(2).account, "bb1153");
+        assert_eq!(resolved(3).account, "bb1153");
+        assert_eq!(resolved(3).time, "01:00:00");
+        assert!(presets.get(3).unwrap().account.is_empty());
+    }
+}
@@ -1,21 +1,59 @@
 use std::{
-    fs::{File, OpenOptions},
-    net::TcpStream,
-    io::{self, Read, prelude::*} 
+    collections::HashMap,
+    fs::File,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    sync::atomic::{AtomicU32, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+    io::{self, Read}
 };
-use ssh2::Session;
+use crate::progress::{Aborted, Progress, Stage};
+use crate::menus::connection_error::ConnectionError;
+use ssh2::{ExtendedData, Session};
 use serde::{Serialize, Deserialize};
-use color_eyre::Result;
-use crate::double_column_menu::entry::Entry;
+use color_eyre::{Result, eyre::{bail, eyre}};
+use crate::double_column_menu::{
+    entry::{Entry, format_bool, parse_bool},
+    toml_list::{Migrate, Migration, TomlList, insert_default}};
+use crate::config::config_dir;
+use crate::paths::{expand_local, parse_environment};
+use crate::menus::ssh_keys::{
+    certificate_expiry, format_expiry_warning, is_certificate_blob, is_security_key};
+use chrono::Local;
+use crate::menus::cluster_menu::CLUSTER_FILE;
+
+/// How long a forwarded command blocks before checking for an abort.
+const POLL_TIMEOUT_MS: u32 = 500;
+
+/// Interval of the keepalive messages, such that firewalls do not drop
+/// idle connections.
+const KEEPALIVE_INTERVAL_S: u32 = 30;
+
+/// How long the check of a connection may take before it counts as dead.
+const ALIVE_TIMEOUT_MS: u32 = 5000;
+
+/// Socket of the shared master connection, `%C` is a hash of the host,
+/// the port and the user, such that the path stays short.
+const CONTROL_PATH: &str = "~/.ssh/code-remote-%C";
+
+/// How long the master connection stays open after the last session.
+const CONTROL_PERSIST: &str = "10m";
+
+/// Subdirectory of the configuration directory with the preset files.
+pub const PRESET_DIR: &str = "presets";
 use std::fs;
 use regex::Regex;
 
-#[derive(Debug, Default, PartialEq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub enum SessionType {
     #[default]
     IdentityFile,
     Passphrase,
     Password,
+    /// The keys of the ssh agent, for certificates and security keys.
+    Agent,
 }
 
 #[derive(Debug, PartialEq)]
@@ -25,6 +63,7 @@ pub enum ClusterError {
     EmptyUser,
     EmptyIdentityFile,
     NoneExistingIdentityFile,
+    NoneExistingCertificateFile,
 }
 
 impl std::fmt::Display for ClusterError {
@@ -40,6 +79,39 @@ impl std::fmt::Display for ClusterError {
                 f, "Cluster identity file is empty"),
             ClusterError::NoneExistingIdentityFile => write!(
                 f, "Cluster identity file does not exist"),
+            ClusterError::NoneExistingCertificateFile => write!(
+                f, "Cluster certificate file does not exist"),
+        }
+    }
+}
+
+impl std::error::Error for ClusterError {}
+
+/// A remote command that exited with a non-zero status.
+#[derive(Debug, PartialEq)]
+pub struct CommandFailed {
+    pub command: String,
+    pub exit_status: i32,
+}
+
+impl std::fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "`{}` exited with status {}", self.command, self.exit_status)
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+impl ClusterError {
+    /// The index of the invalid field in the info pane.
+    pub fn field(&self) -> usize {
+        match self {
+            ClusterError::EmptyName => 0,
+            ClusterError::EmptyHost => 1,
+            ClusterError::EmptyUser => 2,
+            ClusterError::EmptyIdentityFile
+            | ClusterError::NoneExistingIdentityFile => 3,
+            ClusterError::NoneExistingCertificateFile => 6,
         }
     }
 }
@@ -48,12 +120,67 @@ impl std::fmt::Display for ClusterError {
 //            CLUSTER STRUCT
 // =======================================================================
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Cluster {
+    /// Stable identifier of the cluster, it names the preset file and
+    /// does not change when the cluster is renamed.
+    pub id: String,
     pub name: String,
     pub host: String,
     pub user: String,
     pub identity_file: String,
+    pub preseed_vscode_server: bool,
+    /// Directory with read-only preset files, e.g. a team repository.
+    pub shared_presets: String,
+    /// OpenSSH certificate of the identity file, signed by the CA of the
+    /// site. Clusters with a certificate authenticate through the agent.
+    pub certificate_file: String,
+    /// Share one master connection between all ssh connections to the
+    /// cluster, such that VS Code reconnects without authenticating.
+    pub multiplex: bool,
+}
+
+impl Migrate for Cluster {
+    fn migrations() -> Vec<Migration> {
+        vec![
+            // 0 -> 1: seed the VS Code server from the login node
+            |entry| insert_default(entry, "preseed_vscode_server", false.into()),
+            // 1 -> 2: presets are stored in presets/<id>.toml
+            |entry| insert_default(entry, "id", new_cluster_id().into()),
+            // 2 -> 3: shared preset directory
+            |entry| insert_default(entry, "shared_presets", "".into()),
+            // 3 -> 4: certificate file
+            |entry| insert_default(entry, "certificate_file", "".into()),
+            // 4 -> 5: connection multiplexing
+            |entry| insert_default(entry, "multiplex", false.into()),
+        ]
+    }
+}
+
+impl Default for Cluster {
+    fn default() -> Cluster {
+        Cluster {
+            id: new_cluster_id(),
+            name: String::new(),
+            host: String::new(),
+            user: String::new(),
+            identity_file: String::new(),
+            preseed_vscode_server: false,
+            shared_presets: String::new(),
+            certificate_file: String::new(),
+            multiplex: false,
+        }
+    }
+}
+
+/// Create a new cluster id from the current time and a counter, such
+/// that ids created in quick succession differ.
+pub fn new_cluster_id() -> String {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis()).unwrap_or_default();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}{:04x}", millis, count & 0xffff)
 }
 
 impl Entry for Cluster {
@@ -71,6 +198,10 @@ impl Entry for Cluster {
             1 => self.host.clone(),
             2 => self.user.clone(),
             3 => self.identity_file.clone(),
+            4 => format_bool(self.preseed_vscode_server),
+            5 => self.shared_presets.clone(),
+            6 => self.certificate_file.clone(),
+            7 => format_bool(self.multiplex),
             _ => String::new(),
         }
     }
@@ -81,6 +212,10 @@ impl Entry for Cluster {
             1 => self.host = value.to_string(),
             2 => self.user = value.to_string(),
             3 => self.identity_file = value.to_string(),
+            4 => self.preseed_vscode_server = parse_bool(value),
+            5 => self.shared_presets = value.to_string(),
+            6 => self.certificate_file = value.to_string(),
+            7 => self.multiplex = parse_bool(value),
             _ => {},
         }
     }
@@ -91,6 +226,10 @@ impl Entry for Cluster {
             "Host: ".to_string(),
             "User: ".to_string(),
             "IdentityFile: ".to_string(),
+            "Seed Server: ".to_string(),
+            "Shared Presets: ".to_string(),
+            "Certificate: ".to_string(),
+            "Multiplex: ".to_string(),
         ]
     }
 
@@ -100,8 +239,30 @@ impl Entry for Cluster {
             self.host.clone(),
             self.user.clone(),
             self.identity_file.clone(),
+            format_bool(self.preseed_vscode_server),
+            self.shared_presets.clone(),
+            self.certificate_file.clone(),
+            format_bool(self.multiplex),
         ]
     }
+
+    /// Show where the paths point to if they contain `~` or variables.
+    fn get_info_text(&self) -> Vec<String> {
+        let mut info = Vec::new();
+        let path = self.identity_path();
+        if path != self.identity_file {
+            info.push(format!("Identity file: {}", path));
+        }
+        let path = self.certificate_path();
+        if path != self.certificate_file {
+            info.push(format!("Certificate: {}", path));
+        }
+        info
+    }
+
+    fn get_field_errors(&self) -> Vec<(usize, String)> {
+        self.validate().iter().map(|e| (e.field(), e.to_string())).collect()
+    }
 }
 
 impl Cluster {
@@ -114,12 +275,118 @@ impl Cluster {
             host: host.to_string(),
             user: user.to_string(),
             identity_file: identity_file.to_string(),
+            ..Default::default()
         }
     }
 
+    // =======================================================================
+    //            VALIDATION
+    // =======================================================================
+
+    /// Check the fields of the cluster. An empty identity file is valid,
+    /// the password is asked for then.
+    pub fn validate(&self) -> Vec<ClusterError> {
+        let mut errors = Vec::new();
+        if self.name.trim().is_empty() {
+            errors.push(ClusterError::EmptyName);
+        }
+        if self.host.trim().is_empty() {
+            errors.push(ClusterError::EmptyHost);
+        }
+        if self.user.trim().is_empty() {
+            errors.push(ClusterError::EmptyUser);
+        }
+        if !self.identity_file.is_empty() && !Path::new(&self.identity_path()).is_file() {
+            errors.push(ClusterError::NoneExistingIdentityFile);
+        }
+        if !self.certificate_file.is_empty() && !Path::new(&self.certificate_path()).is_file() {
+            errors.push(ClusterError::NoneExistingCertificateFile);
+        }
+        errors
+    }
+
+    /// Check the cluster before connecting with the given session type.
+    pub fn check(&self, session_type: &SessionType) -> Result<()> {
+        if let Some(error) = self.validate().into_iter().next() {
+            return Err(eyre!(error));
+        }
+        let needs_key = matches!(session_type, SessionType::IdentityFile | SessionType::Passphrase);
+        if needs_key && self.identity_file.is_empty() {
+            return Err(eyre!(ClusterError::EmptyIdentityFile));
+        }
+        Ok(())
+    }
+
     // =======================================================================
     //            FILE OPERATIONS
     // =======================================================================
+
+    /// The identity file with `~` and variables expanded.
+    pub fn identity_path(&self) -> String {
+        expand_local(&self.identity_file)
+    }
+
+    /// The certificate file with `~` and variables expanded.
+    pub fn certificate_path(&self) -> String {
+        expand_local(&self.certificate_file)
+    }
+
+    /// How to authenticate: certificates and security keys only work
+    /// through the ssh agent, other keys are read from the identity file,
+    /// and without an identity file the password is asked for.
+    pub fn session_type(&self) -> SessionType {
+        if !self.certificate_file.is_empty() || is_security_key(&self.identity_path()) {
+            SessionType::Agent
+        } else if self.identity_file.is_empty() {
+            SessionType::Password
+        } else {
+            SessionType::IdentityFile
+        }
+    }
+
+    /// A warning if the certificate expired or expires soon.
+    pub fn certificate_warning(&self) -> Option<String> {
+        if self.certificate_file.is_empty() {
+            return None;
+        }
+        match certificate_expiry(&self.certificate_path()) {
+            Ok(Some(valid_until)) => format_expiry_warning(
+                &self.certificate_file, valid_until, Local::now()),
+            Ok(None) => None,
+            Err(e) => Some(format!(
+                "Could not read the certificate {}: {}", self.certificate_file, e)),
+        }
+    }
+
+    /// The file with the spawner presets of this cluster, relative to the
+    /// configuration directory and without extension.
+    pub fn preset_file(&self) -> String {
+        format!("{}/{}", PRESET_DIR, self.id)
+    }
+
+    /// Before version 2 the presets were stored in `<name>.toml` next to
+    /// the cluster file. Move them to `presets/<id>.toml`. This does not
+    /// depend on the upgrade of the cluster file, such that a move that
+    /// failed is retried on the next start.
+    pub fn move_legacy_presets(clusters: &TomlList<Cluster>) -> Result<()> {
+        Self::move_legacy_presets_in(&config_dir()?, &clusters.entry)
+    }
+
+    pub fn move_legacy_presets_in(dir: &Path, clusters: &[Cluster]) -> Result<()> {
+        for cluster in clusters.iter() {
+            // the cluster list itself is not a preset file
+            if cluster.name == CLUSTER_FILE {
+                continue;
+            }
+            let old_file = dir.join(format!("{}.toml", cluster.name));
+            let new_file = dir.join(format!("{}.toml", cluster.preset_file()));
+            if old_file.is_file() && !new_file.exists() {
+                fs::create_dir_all(dir.join(PRESET_DIR))?;
+                fs::rename(&old_file, &new_file)?;
+            }
+        }
+        Ok(())
+    }
     
     /// Format the cluster entry for the ssh config file
     fn format_config_entry(&self) -> String {
@@ -129,7 +396,15 @@ impl Cluster {
         entry.push_str(&format!("    HostName {}\n", self.host));
         entry.push_str(&format!("    User {}\n", self.user));
         if !self.identity_file.is_empty() {
-            entry.push_str(&format!("    IdentityFile {}\n", self.identity_file));
+            entry.push_str(&format!("    IdentityFile {}\n", self.identity_path()));
+        }
+        if !self.certificate_file.is_empty() {
+            entry.push_str(&format!("    CertificateFile {}\n", self.certificate_path()));
+        }
+        if self.multiplex {
+            entry.push_str("    ControlMaster auto\n");
+            entry.push_str(&format!("    ControlPath {}\n", CONTROL_PATH));
+            entry.push_str(&format!("    ControlPersist {}\n", CONTROL_PERSIST));
         }
         entry.push_str(&format!("# code-remote: end {}", self.name));
         entry
@@ -138,59 +413,113 @@ impl Cluster {
     /// Add the cluster to the ssh config file
     pub fn add_cluster_to_ssh_config(&self) -> Result<()>{
         // Read the contents of the .ssh/config file
-        let home = std::env::var("HOME")?;
-        let config_file_path = format!("{}/.ssh/config", home);
-        let config_content = fs::read_to_string(&config_file_path)?;
+        let (config_file_path, config_content) = read_ssh_config()?;
+        let modified_content = Self::replace_config_entry(
+            &config_content, &self.name, &self.format_config_entry())?;
+        fs::write(&config_file_path, modified_content)?;
+        Ok(())
+    }
 
-        // Define the regex pattern to match the start and end of the code remote entry
-        let pattern = format!(r"(?ms)^# code-remote: start {}\n.*?# code-remote: end {}\s*", self.name, self.name);
+    /// Remove the code-remote entry with the given name from the content
+    /// of a ssh config file and append the new entry. The name is matched
+    /// literally, e.g. `gpu(1)` or `a.b`.
+    pub fn replace_config_entry(config_content: &str, name: &str, entry: &str) -> Result<String> {
+        let name = regex::escape(name);
+        let re = Regex::new(&format!(
+            r"(?ms)^# code-remote: start {}\n.*?^# code-remote: end {}$\s*", name, name))?;
+        let mut modified_content = re.replace_all(config_content, "").to_string();
+        modified_content.push_str(entry);
+        modified_content.push('\n');
+        Ok(modified_content)
+    }
 
-        // Create a regex object
-        let re = Regex::new(&pattern)?;
+    /// Remove all entries that code-remote added to the ssh config file.
+    /// Returns the number of removed entries.
+    pub fn clean_ssh_config() -> Result<usize> {
+        let (config_file_path, config_content) = read_ssh_config()?;
+        let (modified_content, count) = Self::remove_config_entries(&config_content)?;
+        fs::write(&config_file_path, modified_content)?;
+        Ok(count)
+    }
 
-        // Replace the code remote entry with an empty string
-        let modified_content = re.replace_all(&config_content, "").to_string();
+    /// Remove all code-remote entries from the content of a ssh config file.
+    pub fn remove_config_entries(config_content: &str) -> Result<(String, usize)> {
+        let re = Regex::new(
+            r"(?ms)^# code-remote: start [^\n]*\n.*?^# code-remote: end [^\n]*\n?")?;
+        let count = re.find_iter(config_content).count();
+        Ok((re.replace_all(config_content, "").to_string(), count))
+    }
+    
+    // =======================================================================
+    //             MASTER CONNECTION
+    // =======================================================================
 
-        // Write the modified content back to the file
-        fs::write(&config_file_path, modified_content)?;
-        
-        let entry = self.format_config_entry();
-        let mut file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(&config_file_path)?;
-        writeln!(file, "{}", entry)?;
+    /// Check whether the master connection of the cluster is running.
+    pub fn check_master(&self) -> Result<bool> {
+        Ok(self.control_master("check")?.status.success())
+    }
+
+    /// Close the master connection. Sessions that use it are closed too.
+    pub fn exit_master(&self) -> Result<()> {
+        let output = self.control_master("exit")?;
+        if !output.status.success() {
+            bail!("Could not close the master connection of {}: {}",
+                  self.name, String::from_utf8_lossy(&output.stderr).trim());
+        }
         Ok(())
     }
-    
+
+    /// Send a control command to the master connection with `ssh -O`.
+    fn control_master(&self, command: &str) -> Result<Output> {
+        if !self.multiplex {
+            bail!("Multiplexing is disabled for {}", self.name);
+        }
+        Command::new("ssh").args(["-O", command, &format!("cr-{}", self.name)])
+            .output()
+            .map_err(|e| eyre!("Could not run ssh: {}", e))
+    }
+
     // =======================================================================
     //             SSH OPERATIONS
     // =======================================================================
 
     /// Read the private key from the identity file
     pub fn read_private_key(&self) -> Result<String> {
-        let mut file = File::open(&self.identity_file)?;
+        let mut file = File::open(self.identity_path())?;
         let mut private_key_str = String::new();
         file.read_to_string(&mut private_key_str)?;
         Ok(private_key_str)
     }
 
-    pub fn create_session(&self, session_type: &SessionType, password: &str) -> Result<Session> {
+    pub fn create_session(&self, session_type: &SessionType, password: &str,
+                          progress: &Progress) -> Result<Session> {
+        self.check(session_type)?;
         // read the private key from the identity file
         let private_key = match session_type {
-            SessionType::IdentityFile => self.read_private_key()?,
-            SessionType::Passphrase => self.read_private_key()?,
-            _ => String::new(),
+            SessionType::Password | SessionType::Agent => String::new(),
+            _ => self.read_private_key().map_err(|e| ConnectionError::IdentityFile {
+                path: self.identity_path(), message: e.to_string(),
+            })?,
         };
         // Connect to the Host (check if the host is reachable)
-        let tcp = TcpStream::connect(format!("{}:22", &self.host))?;
+        progress.stage(Stage::Connecting)?;
+        let addresses: Vec<SocketAddr> = (self.host.as_str(), 22).to_socket_addrs()
+            .map_err(|e| ConnectionError::from_lookup(&self.host, e))?
+            .collect();
+        let tcp = TcpStream::connect(&addresses[..])
+            .map_err(|e| ConnectionError::from_connect(&self.host, e))?;
         // Create a new session
         let mut sess = Session::new()?;
         sess.set_tcp_stream(tcp);
-        sess.handshake()?;
+        sess.handshake()
+            .map_err(|e| ConnectionError::from_handshake(&self.host, e))?;
         // Try to authenticate
+        progress.stage(Stage::Authenticating)?;
+        let allowed = sess.auth_methods(&self.user).ok().map(|m| m.to_string());
+        let method = match session_type {
+            SessionType::Password => "password",
+            _ => "publickey",
+        };
         match session_type {
             SessionType::IdentityFile => {
                 sess.userauth_pubkey_memory(
@@ -201,30 +530,136 @@ impl Cluster {
                     &self.user, None, &private_key, Some(password))
             },
             SessionType::Password => {
-                sess.userauth_password(&self.user, &password)
+                sess.userauth_password(&self.user, password)
             },
-        }?;
+            SessionType::Agent => self.userauth_agent(&sess)?,
+        }.map_err(|e| ConnectionError::from_auth(method, allowed.as_deref(), e))?;
+        sess.set_keepalive(true, KEEPALIVE_INTERVAL_S);
         Ok(sess)
     }
 
-    /// Execute a command and forward the output to the terminal
-    pub fn execute_and_forward(&self, session: &Session, command: &str) -> Result<()>{
+    /// Authenticate with the keys of the ssh agent, certificates first.
+    /// Fails if the agent cannot be used, otherwise the result of the
+    /// last attempt is returned.
+    fn userauth_agent(&self, sess: &Session) -> Result<Result<(), ssh2::Error>> {
+        let agent_error = |e: ssh2::Error| ConnectionError::Agent(e.message().to_string());
+        let mut agent = sess.agent().map_err(agent_error)?;
+        agent.connect().map_err(agent_error)?;
+        agent.list_identities().map_err(agent_error)?;
+        let mut identities = agent.identities().map_err(agent_error)?;
+        if identities.is_empty() {
+            bail!(ConnectionError::Agent("The agent holds no keys".to_string()));
+        }
+        identities.sort_by_key(|identity| !is_certificate_blob(identity.blob()));
+        let mut result = Ok(());
+        for identity in identities.iter() {
+            result = agent.userauth(&self.user, identity);
+            if result.is_ok() {
+                break;
+            }
+        }
+        let _ = agent.disconnect();
+        Ok(result)
+    }
+
+    /// Execute a command and return its exit status and standard output.
+    pub fn execute_and_capture(&self, session: &Session, command: &str) -> Result<(i32, String)> {
+        let mut channel = session.channel_session()?;
+        channel.exec(command)?;
+        let mut output = String::new();
+        channel.read_to_string(&mut output)?;
+        channel.wait_close()?;
+        Ok((channel.exit_status()?, output))
+    }
+
+    /// Execute a command, forward the output to the progress and return it.
+    /// Fails if the command exits with a non-zero status, or with
+    /// [`Aborted`] if the user aborts the task while the command runs.
+    pub fn execute_and_forward(&self, session: &Session, command: &str,
+                               progress: &Progress) -> Result<String>{
         let mut channel = session.channel_session()?;
+        // merge stderr into stdout such that both arrive in order
+        channel.handle_extended_data(ExtendedData::Merge)?;
         channel.exec(command)?;
 
-        println!("{}", command);
+        progress.output(&format!("{}\n", command));
 
-        let mut ssh_stderr = channel.stderr();
-        let stderr = io::stderr();
-        let mut stderr = stderr.lock();
-        io::copy(&mut ssh_stderr, &mut stderr)?;
+        // wake up regularly to check whether the task was aborted
+        let timeout = session.timeout();
+        session.set_timeout(POLL_TIMEOUT_MS);
+        let mut output = Vec::new();
+        let mut buffer = [0; 4096];
+        let result = loop {
+            if progress.is_aborted() {
+                break Err(eyre!(Aborted));
+            }
+            match channel.read(&mut buffer) {
+                Ok(0) => break Ok(()),
+                Ok(n) => {
+                    progress.output(&String::from_utf8_lossy(&buffer[..n]));
+                    output.extend_from_slice(&buffer[..n]);
+                },
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {},
+                Err(e) => break Err(e.into()),
+            }
+        };
+        session.set_timeout(timeout);
+        result?;
 
         channel.wait_close()?;
-        Ok(())
+        let exit_status = channel.exit_status()?;
+        if exit_status != 0 {
+            bail!(CommandFailed { command: command.to_string(), exit_status });
+        }
+        Ok(String::from_utf8_lossy(&output).to_string())
     }
 
 }
 
+/// Check whether the connection still works, e.g. after the laptop was
+/// suspended, by opening a channel.
+pub fn is_alive(session: &Session) -> bool {
+    if !session.authenticated() {
+        return false;
+    }
+    let timeout = session.timeout();
+    session.set_timeout(ALIVE_TIMEOUT_MS);
+    let alive = session.channel_session().and_then(|mut channel| channel.close()).is_ok();
+    session.set_timeout(timeout);
+    alive
+}
+
+/// Read the environment of the login node, to expand paths on the
+/// remote side.
+pub fn read_remote_environment(session: &Session) -> Result<HashMap<String, String>> {
+    let mut channel = session.channel_session()?;
+    channel.exec("env")?;
+    let mut output = String::new();
+    channel.read_to_string(&mut output)?;
+    channel.wait_close()?;
+    Ok(parse_environment(&output))
+}
+
+/// Read the ssh config file of the user and return its path and content.
+/// A missing file is read as empty, such that it is created on write.
+pub fn read_ssh_config() -> Result<(PathBuf, String)> {
+    read_ssh_config_in(Path::new(&std::env::var("HOME")?))
+}
+
+pub fn read_ssh_config_in(home: &Path) -> Result<(PathBuf, String)> {
+    let ssh_dir = home.join(".ssh");
+    if !ssh_dir.exists() {
+        fs::create_dir_all(&ssh_dir)?;
+        fs::set_permissions(&ssh_dir, fs::Permissions::from_mode(0o700))?;
+    }
+    let path = ssh_dir.join("config");
+    match fs::read_to_string(&path) {
+        Ok(content) => Ok((path, content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok((path, String::new())),
+        Err(e) => Err(eyre!("Could not read {}: {}", path.display(), e)),
+    }
+}
+
 // Tests 
 #[cfg(test)]
 mod tests {
@@ -241,6 +676,41 @@ mod tests {
         assert!(Path::new("/tmp/id_rsa").exists());
     }
 
+    #[test]
+    fn test_remove_config_entries() {
+        let content = "Host other\n    User me\n\
+                       # code-remote: start levante\n\
+                       Host cr-levante\n    HostName levante.dkrz.de\n\
+                       # code-remote: end levante\n\
+                       # code-remote: start gpu\n\
+                       Host cr-gpu\n    HostName node1\n\
+                       # code-remote: end gpu\n";
+        let (content, count) = Cluster::remove_config_entries(content).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(content, "Host other\n    User me\n");
+    }
+
+    #[test]
+    fn test_replace_config_entry() {
+        let entry = |name: &str, node: &str| format!(
+            "# code-remote: start {name}\nHost cr-{name}\n    HostName {node}\n\
+             # code-remote: end {name}");
+        let mut content = format!("{}\n{}\n", entry("gpu1", "node0"), entry("a.b", "node0"));
+        for node in ["node1", "node2"] {
+            content = Cluster::replace_config_entry(
+                &content, "gpu(1)", &entry("gpu(1)", node)).unwrap();
+        }
+        assert_eq!(content.matches("Host cr-gpu(1)\n").count(), 1);
+        assert!(content.contains("HostName node2"));
+        assert!(!content.contains("HostName node1"));
+        // other entries with similar names are kept
+        let content = Cluster::replace_config_entry(&content, "a+b", &entry("a+b", "n")).unwrap();
+        let content = Cluster::replace_config_entry(&content, "a", &entry("a", "n")).unwrap();
+        for name in ["gpu1", "a.b", "a+b", "a"] {
+            assert_eq!(content.matches(&format!("Host cr-{}\n", name)).count(), 1, "{}", name);
+        }
+    }
+
     #[test]
     fn test_new_cluster() {
         create_tmp_file();
@@ -252,5 +722,111 @@ mod tests {
         assert_eq!(cluster.identity_file, "/tmp/id_rsa");
     }
 
-}
+    #[test]
+    fn test_cluster_ids_differ() {
+        let first = Cluster::new("a", "h", "u", "");
+        let second = Cluster::new("a", "h", "u", "");
+        assert!(!first.id.is_empty());
+        assert_ne!(first.id, second.id);
+        assert_eq!(first.preset_file(), format!("presets/{}", first.id));
+    }
+
+    #[test]
+    fn test_move_legacy_presets() {
+        let dir = tempfile::tempdir().unwrap();
+        let levante = Cluster::new("levante", "h", "u", "");
+        let clusters = Cluster::new("clusters", "h", "u", "");
+        fs::write(dir.path().join("levante.toml"), "[[entry]]\n").unwrap();
+        fs::write(dir.path().join("clusters.toml"), "[[entry]]\n").unwrap();
+
+        Cluster::move_legacy_presets_in(
+            dir.path(), &[levante, clusters]).unwrap();
+        assert!(!dir.path().join("levante.toml").exists());
+        assert_eq!(fs::read_dir(dir.path().join(PRESET_DIR)).unwrap().count(), 1);
+        // the cluster list is never moved
+        assert!(dir.path().join("clusters.toml").exists());
+
+        // a move that failed before is retried, an existing file is kept
+        let mistral = Cluster::new("mistral", "h", "u", "");
+        fs::write(dir.path().join("mistral.toml"), "[[entry]]\n").unwrap();
+        fs::write(dir.path().join("levante.toml"), "[[entry]]\n").unwrap();
+        Cluster::move_legacy_presets_in(dir.path(), &[mistral]).unwrap();
+        assert!(!dir.path().join("mistral.toml").exists());
+        assert_eq!(fs::read_dir(dir.path().join(PRESET_DIR)).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_read_missing_ssh_config() {
+        let home = tempfile::tempdir().unwrap();
+        let (path, content) = read_ssh_config_in(home.path()).unwrap();
+        assert_eq!(path, home.path().join(".ssh").join("config"));
+        assert!(content.is_empty());
+        let mode = fs::metadata(home.path().join(".ssh")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[test]
+    fn test_validate() {
+        let cluster = Cluster::new("", "levante.dkrz.de", " ", "");
+        assert_eq!(cluster.validate(), vec![ClusterError::EmptyName, ClusterError::EmptyUser]);
+        assert_eq!(cluster.get_field_errors()[1], (2, "Cluster user is empty".to_string()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("id_ed25519");
+        let mut cluster = Cluster::new("levante", "levante.dkrz.de", "u", "");
+        assert!(cluster.check(&SessionType::Password).is_ok());
+        let error = cluster.check(&SessionType::IdentityFile).unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&ClusterError::EmptyIdentityFile));
+        cluster.identity_file = key.to_string_lossy().to_string();
+        assert_eq!(cluster.validate(), vec![ClusterError::NoneExistingIdentityFile]);
+        fs::write(&key, "").unwrap();
+        assert!(cluster.validate().is_empty());
+    }
 
+    #[test]
+    fn test_identity_path() {
+        let home = std::env::var("HOME").unwrap();
+        let cluster = Cluster::new("levante", "h", "u", "~/.ssh/id_ed25519");
+        assert_eq!(cluster.identity_path(), format!("{}/.ssh/id_ed25519", home));
+        assert_eq!(cluster.get_info_text(),
+                   vec![format!("Identity file: {}/.ssh/id_ed25519", home)]);
+        assert!(cluster.format_config_entry()
+                .contains(&format!("IdentityFile {}/.ssh/id_ed25519", home)));
+        let cluster = Cluster::new("levante", "h", "u", "/keys/id_ed25519");
+        assert!(cluster.get_info_text().is_empty());
+    }
+
+    #[test]
+    fn test_multiplex_config_entry() {
+        let mut cluster = Cluster::new("levante", "levante.dkrz.de", "u", "");
+        assert!(!cluster.format_config_entry().contains("ControlMaster"));
+        cluster.multiplex = true;
+        let entry = cluster.format_config_entry();
+        assert!(entry.contains("    ControlMaster auto\n    ControlPath ~/.ssh/code-remote-%C\n"));
+        assert!(entry.ends_with("    ControlPersist 10m\n# code-remote: end levante"));
+    }
+
+    #[test]
+    fn test_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("id_ed25519");
+        let key = key.to_str().unwrap();
+        let mut cluster = Cluster::new("levante", "levante.dkrz.de", "u", key);
+        assert_eq!(cluster.session_type(), SessionType::IdentityFile);
+        assert_eq!(cluster.certificate_warning(), None);
+
+        // security keys are only usable through the agent
+        fs::write(format!("{}.pub", key), "sk-ssh-ed25519@openssh.com AAAA u@host\n").unwrap();
+        assert_eq!(cluster.session_type(), SessionType::Agent);
+        assert!(cluster.check(&SessionType::Agent).is_err());
+
+        cluster.certificate_file = format!("{}-cert.pub", key);
+        assert_eq!(cluster.validate(), vec![
+            ClusterError::NoneExistingIdentityFile, ClusterError::NoneExistingCertificateFile]);
+        assert!(cluster.format_config_entry()
+                .contains(&format!("    CertificateFile {}-cert.pub\n", key)));
+        // an agent does not need the identity file
+        let cluster = Cluster { identity_file: String::new(), ..cluster };
+        assert_eq!(cluster.session_type(), SessionType::Agent);
+    }
+}
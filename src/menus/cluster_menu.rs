@@ -1,18 +1,26 @@
+use std::collections::HashMap;
 use tui_textarea::{TextArea};
+use color_eyre::eyre::Result;
+use chrono::{DateTime, Local};
+use crossterm::event::{KeyCode, KeyEvent};
 
-use crate::menus::{cluster::Cluster};
+use crate::menus::{cluster::Cluster, spawner::Spawner};
 use crate::double_column_menu::{
+    entry::Entry,
     counter::Counter,
     toml_list::TomlList,
     double_column_menu::{DoubleColumnMenu, Focus, InputMode}};
 
 use crate::app::{Action};
 
-const CLUSTER_FILE: &str = "clusters";
-const MAX_INFO_COUNTER: u32 = 4;
+pub const CLUSTER_FILE: &str = "clusters";
+const MAX_INFO_COUNTER: u32 = 8;
 
 #[derive(Debug)]
 pub struct ClusterMenu {
+    /// Whether the master connection was running when it was last
+    /// checked, by cluster id.
+    pub master_status: HashMap<String, (bool, DateTime<Local>)>,
     pub list_counter: Counter,
     pub info_counter: Counter,
     entries: TomlList<Cluster>,
@@ -25,9 +33,10 @@ impl Default for ClusterMenu {
     fn default() -> Self {
         let entries: TomlList<Cluster> = TomlList::new();
         ClusterMenu {
+            master_status: HashMap::new(),
             list_counter: Counter::new(1),
             info_counter: Counter::new(MAX_INFO_COUNTER),
-            entries: entries,
+            entries,
             focus: Focus::default(),
             input_mode: InputMode::default(),
             text_area: TextArea::default(),
@@ -94,7 +103,46 @@ impl DoubleColumnMenu<Cluster> for ClusterMenu {
 
     fn action_left(&mut self, _action: &mut Action) {
         // do nothing
-        return;
+    }
+
+    /// Check or close the master connection.
+    fn input_other(&mut self, action: &mut Action, key_event: KeyEvent) {
+        // a bundle can be imported into an empty list as well
+        if let KeyCode::Char('I') = key_event.code {
+            *action = Action::OpenImport;
+            return;
+        }
+        if self.is_new_entry() || self.is_shared_entry() {
+            return;
+        }
+        match key_event.code {
+            KeyCode::Char('m') => *action = Action::CheckMaster,
+            KeyCode::Char('x') => *action = Action::ExitMaster,
+            KeyCode::Char('E') => *action = Action::OpenExport,
+            _ => {},
+        }
+    }
+
+    /// Show the state of the master connection of multiplexed clusters.
+    fn get_display_info_text(&self) -> Vec<String> {
+        let Ok(entry) = self.get_entry() else {
+            return Vec::new();
+        };
+        let mut info = entry.get_info_text();
+        if entry.multiplex {
+            info.push(match self.master_status.get(&entry.id) {
+                Some((running, checked)) => format!(
+                    "Master connection: {} (checked {})",
+                    if *running { "running" } else { "not running" },
+                    checked.format("%H:%M:%S")),
+                None => "Master connection: `m` to check, `x` to close".to_string(),
+            });
+        }
+        info
+    }
+
+    fn on_remove(&mut self, entry: &Cluster) -> Result<()> {
+        TomlList::<Spawner>::remove(&entry.preset_file())
     }
 
 }
@@ -134,4 +182,17 @@ mod tests {
         cluster_menu.list_counter.increment();
         assert!(cluster_menu.is_new_entry());
     }
+
+    #[test]
+    fn test_master_status() {
+        let mut cluster_menu = create_dummy_cluster_menu().unwrap();
+        assert!(cluster_menu.get_display_info_text().is_empty());
+        cluster_menu.get_entry_mut().unwrap().multiplex = true;
+        assert_eq!(cluster_menu.get_display_info_text(),
+                   vec!["Master connection: `m` to check, `x` to close"]);
+        let id = cluster_menu.get_entry().unwrap().id.clone();
+        cluster_menu.master_status.insert(id, (true, Local::now()));
+        assert!(cluster_menu.get_display_info_text()[0]
+                .starts_with("Master connection: running (checked "));
+    }
 }
@@ -0,0 +1,258 @@
+use std::{fmt, io};
+use color_eyre::eyre::{eyre, Report};
+use ssh2::ErrorCode;
+
+use crate::progress::Aborted;
+
+// libssh2 error codes, see libssh2.h
+const LIBSSH2_ERROR_TIMEOUT: i32 = -9;
+const LIBSSH2_ERROR_HOSTKEY_INIT: i32 = -10;
+const LIBSSH2_ERROR_HOSTKEY_SIGN: i32 = -11;
+const LIBSSH2_ERROR_SOCKET_DISCONNECT: i32 = -13;
+const LIBSSH2_ERROR_FILE: i32 = -16;
+const LIBSSH2_ERROR_SOCKET_TIMEOUT: i32 = -30;
+const LIBSSH2_ERROR_KEYFILE_AUTH_FAILED: i32 = -48;
+
+/// Why connecting to a cluster or running a scheduler command failed.
+#[derive(Debug, PartialEq)]
+pub enum ConnectionError {
+    /// The host name could not be resolved.
+    Dns { host: String, message: String },
+    /// The TCP connection could not be established or broke down.
+    Tcp { host: String, kind: io::ErrorKind, message: String },
+    /// The SSH handshake with the server failed.
+    Handshake(String),
+    /// The host key sent by the server could not be loaded, or its
+    /// signature of the key exchange is invalid.
+    HostKey(String),
+    /// The identity file could not be read.
+    IdentityFile { path: String, message: String },
+    /// The private key could not be decoded: it is encrypted and needs a
+    /// passphrase, or the passphrase is wrong.
+    PrivateKey(String),
+    /// The ssh agent is not running or holds no keys.
+    Agent(String),
+    /// The server rejected the user, the key or the password, or does
+    /// not offer the authentication method.
+    AuthMethod { method: String, allowed: Option<String>, message: String },
+    /// A command of the scheduler failed, e.g. `salloc` or `squeue`.
+    Scheduler { command: String, message: String },
+}
+
+impl ConnectionError {
+    /// Map an error of the name lookup.
+    pub fn from_lookup(host: &str, error: io::Error) -> ConnectionError {
+        ConnectionError::Dns { host: host.to_string(), message: error.to_string() }
+    }
+
+    /// Map an error of the TCP connection.
+    pub fn from_connect(host: &str, error: io::Error) -> ConnectionError {
+        ConnectionError::Tcp {
+            host: host.to_string(), kind: error.kind(), message: error.to_string(),
+        }
+    }
+
+    /// Map an error of the SSH handshake.
+    pub fn from_handshake(host: &str, error: ssh2::Error) -> ConnectionError {
+        let message = error.message().to_string();
+        match error.code() {
+            ErrorCode::Session(LIBSSH2_ERROR_HOSTKEY_INIT)
+            | ErrorCode::Session(LIBSSH2_ERROR_HOSTKEY_SIGN) => {
+                ConnectionError::HostKey(message)
+            },
+            ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT)
+            | ErrorCode::Session(LIBSSH2_ERROR_SOCKET_TIMEOUT) => ConnectionError::Tcp {
+                host: host.to_string(), kind: io::ErrorKind::TimedOut, message,
+            },
+            _ => ConnectionError::Handshake(message),
+        }
+    }
+
+    /// Map an error of the authentication. `allowed` are the methods that
+    /// the server offers, if it told them.
+    pub fn from_auth(method: &str, allowed: Option<&str>, error: ssh2::Error) -> ConnectionError {
+        let message = error.message().to_string();
+        match error.code() {
+            ErrorCode::Session(LIBSSH2_ERROR_FILE)
+            | ErrorCode::Session(LIBSSH2_ERROR_KEYFILE_AUTH_FAILED) => {
+                ConnectionError::PrivateKey(message)
+            },
+            ErrorCode::Session(LIBSSH2_ERROR_SOCKET_DISCONNECT) => {
+                ConnectionError::Handshake(message)
+            },
+            _ => ConnectionError::AuthMethod {
+                method: method.to_string(),
+                allowed: allowed.map(|a| a.to_string()),
+                message,
+            },
+        }
+    }
+
+    /// Wrap the failure of a scheduler command. Errors of the connection
+    /// and aborts are kept as they are.
+    pub fn scheduler(command: &str, error: Report) -> Report {
+        if error.downcast_ref::<ssh2::Error>().is_some()
+            || error.downcast_ref::<io::Error>().is_some()
+            || error.downcast_ref::<Aborted>().is_some() {
+            return error;
+        }
+        eyre!(ConnectionError::Scheduler { command: command.to_string(), message: error.to_string() })
+    }
+
+    /// Check whether the server rejected the credentials themselves, such
+    /// that asking for them again may help.
+    pub fn is_rejected_secret(&self) -> bool {
+        match self {
+            ConnectionError::PrivateKey(_) => true,
+            ConnectionError::AuthMethod { method, allowed, .. } => {
+                allowed.as_deref().is_none_or(|allowed| allowed.contains(method.as_str()))
+            },
+            _ => false,
+        }
+    }
+
+    /// A short name of the variant, used in the JSON output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ConnectionError::Dns { .. } => "dns",
+            ConnectionError::Tcp { .. } => "tcp",
+            ConnectionError::Handshake(_) => "handshake",
+            ConnectionError::HostKey(_) => "host_key",
+            ConnectionError::IdentityFile { .. } => "identity_file",
+            ConnectionError::PrivateKey(_) => "private_key",
+            ConnectionError::Agent(_) => "agent",
+            ConnectionError::AuthMethod { .. } => "auth_method",
+            ConnectionError::Scheduler { .. } => "scheduler",
+        }
+    }
+
+    /// What the user can do about the error.
+    pub fn remediation(&self) -> String {
+        match self {
+            ConnectionError::Dns { .. } => {
+                "Please check the host name, or your VPN if the cluster is only \
+                 reachable from the internal network.".to_string()
+            },
+            ConnectionError::Tcp { kind: io::ErrorKind::ConnectionRefused, .. } => {
+                "Please check that the host runs an SSH server on port 22.".to_string()
+            },
+            ConnectionError::Tcp { .. } => {
+                "Please check your network connection and whether the cluster \
+                 is reachable from here (VPN, firewall).".to_string()
+            },
+            ConnectionError::Handshake(_) => {
+                "The server closed the connection during the SSH handshake. \
+                 Please try again later, or check with `ssh` whether the host \
+                 accepts connections.".to_string()
+            },
+            ConnectionError::HostKey(_) => {
+                "The server sent a host key of an unsupported type, or a \
+                 signature that does not match its key. Please check with `ssh` \
+                 whether the host is the expected server and which host key \
+                 algorithms it offers.".to_string()
+            },
+            ConnectionError::IdentityFile { .. } => {
+                "Please check the path of the identity file.".to_string()
+            },
+            ConnectionError::PrivateKey(_) => {
+                "The private key is encrypted, or the passphrase is wrong.".to_string()
+            },
+            ConnectionError::Agent(_) => {
+                "Certificates and security keys are used through the ssh agent. \
+                 Please start it and add the key with `ssh-add <identity file>`, \
+                 which adds the certificate next to it as well.".to_string()
+            },
+            ConnectionError::AuthMethod { allowed: Some(allowed), method, .. }
+                if !allowed.contains(method.as_str()) => {
+                format!("The server does not accept {} authentication, only: {}.",
+                        method, allowed)
+            },
+            ConnectionError::AuthMethod { .. } => {
+                "Please check the user name and the identity file or password.".to_string()
+            },
+            ConnectionError::Scheduler { .. } => {
+                "Please check the session settings (account, partition, time) \
+                 and the output of the scheduler in the log (`o`).".to_string()
+            },
+        }
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionError::Dns { host, message } => write!(
+                f, "Could not resolve the host name {}: {}", host, message),
+            ConnectionError::Tcp { host, message, .. } => write!(
+                f, "Could not connect to {}: {}", host, message),
+            ConnectionError::Handshake(message) => write!(
+                f, "SSH handshake failed: {}", message),
+            ConnectionError::HostKey(message) => write!(
+                f, "Host key verification failed: {}", message),
+            ConnectionError::IdentityFile { path, message } => write!(
+                f, "Could not read the identity file {}: {}", path, message),
+            ConnectionError::PrivateKey(message) => write!(
+                f, "Could not use the private key: {}", message),
+            ConnectionError::Agent(message) => write!(
+                f, "Could not use the ssh agent: {}", message),
+            ConnectionError::AuthMethod { method, message, .. } => write!(
+                f, "{} authentication failed: {}", method, message),
+            ConnectionError::Scheduler { command, message } => write!(
+                f, "{} failed: {}", command, message),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ssh_error(code: i32) -> ssh2::Error {
+        ssh2::Error::new(ErrorCode::Session(code), "test")
+    }
+
+    #[test]
+    fn test_from_connect() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let error = ConnectionError::from_connect("levante", refused);
+        assert_eq!(error.kind(), "tcp");
+        assert!(error.remediation().contains("port 22"));
+        let unreachable = io::Error::from(io::ErrorKind::TimedOut);
+        let error = ConnectionError::from_connect("levante", unreachable);
+        assert!(error.remediation().contains("VPN"));
+    }
+
+    #[test]
+    fn test_from_handshake() {
+        let error = ConnectionError::from_handshake("levante", ssh_error(LIBSSH2_ERROR_HOSTKEY_INIT));
+        assert_eq!(error.kind(), "host_key");
+        assert!(error.remediation().contains("unsupported type"));
+        let error = ConnectionError::from_handshake("levante", ssh_error(-8));
+        assert_eq!(error.kind(), "handshake");
+    }
+
+    #[test]
+    fn test_from_auth() {
+        let error = ConnectionError::from_auth("publickey", None, ssh_error(LIBSSH2_ERROR_FILE));
+        assert_eq!(error, ConnectionError::PrivateKey("test".to_string()));
+        assert!(error.is_rejected_secret());
+
+        let error = ConnectionError::from_auth(
+            "password", Some("publickey,password"), ssh_error(-18));
+        assert_eq!(error.kind(), "auth_method");
+        assert!(error.is_rejected_secret());
+
+        // asking again does not help if the server does not offer the method
+        let error = ConnectionError::from_auth(
+            "password", Some("publickey,keyboard-interactive"),
+            ssh_error(-18));
+        assert!(!error.is_rejected_secret());
+        assert!(error.remediation().contains("only: publickey,keyboard-interactive"));
+    }
+}
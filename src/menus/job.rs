@@ -0,0 +1,130 @@
+use ssh2::Session;
+use color_eyre::eyre::{eyre, Result};
+use regex::Regex;
+use crate::menus::{cluster::Cluster, connection_error::ConnectionError};
+
+/// Output format for squeue: id, name, state, nodes, partition, time.
+const SQUEUE_FORMAT: &str = "%i|%j|%T|%N|%P|%M";
+
+/// A slurm job of the user on the cluster.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Job {
+    pub id: String,
+    pub name: String,
+    pub state: String,
+    pub node: String,
+    pub partition: String,
+    pub time: String,
+}
+
+impl Job {
+    // =======================================================================
+    //            PARSING
+    // =======================================================================
+
+    /// Parse a single line of squeue output in [`SQUEUE_FORMAT`].
+    pub fn parse(line: &str) -> Option<Job> {
+        let fields: Vec<&str> = line.trim().split('|').collect();
+        if fields.len() != 6 || fields[0].is_empty() {
+            return None;
+        }
+        Some(Job {
+            id: fields[0].to_string(),
+            name: fields[1].to_string(),
+            state: fields[2].to_string(),
+            node: fields[3].to_string(),
+            partition: fields[4].to_string(),
+            time: fields[5].to_string(),
+        })
+    }
+
+    /// Parse the squeue output into a list of jobs.
+    pub fn parse_list(output: &str) -> Vec<Job> {
+        output.lines().filter_map(Job::parse).collect()
+    }
+
+    /// Get the job id from the salloc output.
+    pub fn parse_salloc_output(output: &str) -> Option<String> {
+        let re = Regex::new(r"Granted job allocation (\d+)").unwrap();
+        re.captures(output).map(|c| c[1].to_string())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == "RUNNING"
+    }
+
+    /// Short description for the job selection list.
+    pub fn format_line(&self) -> String {
+        format!("{:<10} {:<20} {:<10} {:<12} {}",
+                self.id, self.name, self.state, self.node, self.time)
+    }
+
+    // =======================================================================
+    //             SSH OPERATIONS
+    // =======================================================================
+
+    fn query(session: &Session, cluster: &Cluster, filter: &str) -> Result<Vec<Job>> {
+        let command = format!(
+            "squeue -u $USER --noheader --format='{}' {}", SQUEUE_FORMAT, filter);
+        let (exit_status, output) = cluster.execute_and_capture(session, &command)?;
+        if exit_status != 0 {
+            return Err(ConnectionError::scheduler(
+                "squeue", eyre!("exit status {}", exit_status)));
+        }
+        Ok(Job::parse_list(&output))
+    }
+
+    /// List all jobs of the user.
+    pub fn list(session: &Session, cluster: &Cluster) -> Result<Vec<Job>> {
+        Job::query(session, cluster, "")
+    }
+
+    /// List the running jobs of the user.
+    pub fn list_running(session: &Session, cluster: &Cluster) -> Result<Vec<Job>> {
+        Job::query(session, cluster, "--states=RUNNING")
+    }
+
+    /// Find a job by its id. Returns `None` if the job does not exist anymore.
+    pub fn find_by_id(session: &Session, cluster: &Cluster, id: &str) -> Result<Option<Job>> {
+        let jobs = Job::list(session, cluster)?;
+        Ok(jobs.into_iter().find(|job| job.id == id))
+    }
+
+    /// Find all jobs with the given name.
+    pub fn find_by_name(session: &Session, cluster: &Cluster, name: &str) -> Result<Vec<Job>> {
+        let jobs = Job::list(session, cluster)?;
+        Ok(jobs.into_iter().filter(|job| job.name == name).collect())
+    }
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_list() {
+        let output = "123|gpu|RUNNING|node01|gpu|1:02:03\n\
+                      124|interactive|PENDING||compute|0:00\n\
+                      garbage\n";
+        let jobs = Job::parse_list(output);
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].id, "123");
+        assert_eq!(jobs[0].node, "node01");
+        assert!(jobs[0].is_running());
+        assert_eq!(jobs[1].name, "interactive");
+        assert!(!jobs[1].is_running());
+    }
+
+    #[test]
+    fn test_parse_salloc_output() {
+        let output = "salloc: Pending job allocation 4242\n\
+                      salloc: job 4242 queued and waiting for resources\n\
+                      salloc: Granted job allocation 4242\n";
+        assert_eq!(Job::parse_salloc_output(output), Some("4242".to_string()));
+        assert_eq!(Job::parse_salloc_output("salloc: error"), None);
+    }
+}
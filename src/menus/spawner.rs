@@ -1,15 +1,111 @@
-use crate::menus::cluster::Cluster;
-use crate::double_column_menu::entry::Entry;
+use crate::menus::{cluster::{Cluster, CommandFailed, read_ssh_config, read_remote_environment}, connection_error::ConnectionError, job::Job, vscode_server};
+use crate::progress::{Progress, Stage};
+use crate::paths::expand_remote;
+use crate::double_column_menu::{
+    entry::Entry,
+    toml_list::{Migrate, Migration, TomlList, insert_default}};
 use ssh2::Session;
-use std::{io::Read, process::Command, default::Default};
-use regex::Regex;
-use color_eyre::eyre::Result;
+use std::{io, process::{Command, Output}, default::Default};
+use color_eyre::eyre::{bail, eyre, Result};
 use serde::{Serialize, Deserialize};
-use std::{fs, fs::OpenOptions, io::Write};
+use std::{collections::HashMap, fs};
+use toml::value::{Table, Value};
 
 
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// What VS Code opens once the node is reachable.
+#[derive(Debug, PartialEq)]
+pub enum EditorTarget {
+    /// A remote `.code-workspace` file, opened with `--file-uri`.
+    Workspace(String),
+    /// One or more remote folders, each opened with `--folder-uri`.
+    /// An empty path stands for the home directory.
+    Folders(Vec<String>),
+}
+
+/// The outcome of a successful spawn.
+#[derive(Debug, PartialEq)]
+pub struct SpawnResult {
+    /// The job the session runs on.
+    pub job: Job,
+    /// The ssh host alias of the compute node.
+    pub alias: String,
+    /// The uris that were opened in VS Code.
+    pub uris: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SpawnerError {
+    EmptyName,
+    InvalidTime(String),
+}
+
+impl std::fmt::Display for SpawnerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SpawnerError::EmptyName => write!(
+                f, "Preset name is empty"),
+            SpawnerError::InvalidTime(time) => write!(
+                f, "Invalid time limit `{}`, expected e.g. 30, 02:00:00 or 1-12", time),
+        }
+    }
+}
+
+impl std::error::Error for SpawnerError {}
+
+impl SpawnerError {
+    /// The index of the invalid field in the info pane.
+    pub fn field(&self) -> usize {
+        match self {
+            SpawnerError::EmptyName => 0,
+            SpawnerError::InvalidTime(_) => 3,
+        }
+    }
+}
+
+/// Parse a Slurm time limit into seconds. The formats are `minutes`,
+/// `minutes:seconds`, `hours:minutes:seconds`, `days-hours`,
+/// `days-hours:minutes` and `days-hours:minutes:seconds`. An unlimited
+/// time is returned as `u64::MAX`.
+pub fn parse_slurm_time(time: &str) -> Option<u64> {
+    let time = time.trim();
+    if matches!(time.to_lowercase().as_str(), "infinite" | "unlimited") {
+        return Some(u64::MAX);
+    }
+    let number = |s: &str| -> Option<u64> {
+        match !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+            true => s.parse().ok(),
+            false => None,
+        }
+    };
+    // minutes and seconds after the leading field are below 60
+    let below = |value: u64, limit: u64| (value < limit).then_some(value);
+    // the sum of the values times their unit in seconds, None on overflow
+    let total = |fields: &[(u64, u64)]| fields.iter()
+        .try_fold(0u64, |sum, (value, unit)| value.checked_mul(*unit)?.checked_add(sum));
+    let (days, clock) = match time.split_once('-') {
+        Some((days, clock)) => (Some(number(days)?), clock),
+        None => (None, time),
+    };
+    let parts: Vec<&str> = clock.split(':').collect();
+    let fields = match (days, parts.as_slice()) {
+        (None, [m]) => vec![(number(m)?, 60)],
+        (None, [m, s]) => vec![(number(m)?, 60), (below(number(s)?, 60)?, 1)],
+        (None, [h, m, s]) => vec![
+            (number(h)?, 3600), (below(number(m)?, 60)?, 60), (below(number(s)?, 60)?, 1)],
+        (Some(_), [h]) => vec![(below(number(h)?, 24)?, 3600)],
+        (Some(_), [h, m]) => vec![(below(number(h)?, 24)?, 3600), (below(number(m)?, 60)?, 60)],
+        (Some(_), [h, m, s]) => vec![
+            (below(number(h)?, 24)?, 3600), (below(number(m)?, 60)?, 60),
+            (below(number(s)?, 60)?, 1)],
+        _ => return None,
+    };
+    total(&fields)?.checked_add(days.unwrap_or(0).checked_mul(86400)?)
+}
+
+/// A session preset. All fields are empty by default, such that they
+/// are inherited.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Spawner {
     pub preset_name: String,
     pub account: String,
@@ -17,18 +113,36 @@ pub struct Spawner {
     pub time: String,
     pub working_directory: String,
     pub other_options: String,
+    pub additional_folders: Vec<String>,
+    pub workspace_file: String,
+    pub pre_spawn_commands: Vec<String>,
+    pub post_start_commands: Vec<String>,
+    pub container_image: String,
+    pub container_binds: Vec<String>,
+    /// Name of the preset that this preset inherits unset fields from.
+    /// Presets without a parent inherit from the defaults of the file.
+    pub extends: String,
+    /// The job of the last spawn or attach. Later actions target this job.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub job_id: String,
 }
 
-impl Default for Spawner {
-    fn default() -> Spawner {
-        Spawner {
-            preset_name: String::from(""),
-            account: String::from(""),
-            partition: String::from(""),
-            time: String::from("01:00:00"),
-            working_directory: String::from(""),
-            other_options: String::from(""),
-        }
+impl Migrate for Spawner {
+    fn migrations() -> Vec<Migration> {
+        vec![
+            // 0 -> 1: multiple folders, workspaces, hooks and containers
+            |entry| {
+                for key in ["additional_folders", "pre_spawn_commands",
+                            "post_start_commands", "container_binds"] {
+                    insert_default(entry, key, Vec::<String>::new().into());
+                }
+                for key in ["workspace_file", "container_image"] {
+                    insert_default(entry, key, "".into());
+                }
+            },
+            // 1 -> 2: preset inheritance
+            |entry| insert_default(entry, "extends", "".into()),
+        ]
     }
 }
 
@@ -49,6 +163,13 @@ impl Entry for Spawner {
             3 => self.time.clone(),
             4 => self.working_directory.clone(),
             5 => self.other_options.clone(),
+            6 => self.additional_folders.join(", "),
+            7 => self.workspace_file.clone(),
+            8 => join_scripts(&self.pre_spawn_commands),
+            9 => join_scripts(&self.post_start_commands),
+            10 => self.container_image.clone(),
+            11 => self.container_binds.join(","),
+            12 => self.extends.clone(),
             _ => String::from(""),
         }
     }
@@ -61,6 +182,13 @@ impl Entry for Spawner {
             3 => self.time = value.to_string(),
             4 => self.working_directory = value.to_string(),
             5 => self.other_options = value.to_string(),
+            6 => self.additional_folders = split_list(value, ','),
+            7 => self.workspace_file = value.to_string(),
+            8 => self.pre_spawn_commands = parse_script(value),
+            9 => self.post_start_commands = parse_script(value),
+            10 => self.container_image = value.to_string(),
+            11 => self.container_binds = split_list(value, ','),
+            12 => self.extends = value.to_string(),
             _ => {},
         }
     }
@@ -73,6 +201,13 @@ impl Entry for Spawner {
             "Max. Time: ".to_string(),
             "Work. Dir.: ".to_string(),
             "Other Options: ".to_string(),
+            "Add. Folders: ".to_string(),
+            "Workspace: ".to_string(),
+            "Pre-Spawn: ".to_string(),
+            "Post-Start: ".to_string(),
+            "Container: ".to_string(),
+            "Binds: ".to_string(),
+            "Extends: ".to_string(),
         ]
     }
 
@@ -84,8 +219,125 @@ impl Entry for Spawner {
             self.time.clone(),
             self.working_directory.clone(),
             self.other_options.clone(),
+            self.additional_folders.join(", "),
+            self.workspace_file.clone(),
+            join_scripts(&self.pre_spawn_commands),
+            join_scripts(&self.post_start_commands),
+            self.container_image.clone(),
+            self.container_binds.join(","),
+            self.extends.clone(),
         ]
     }
+
+    fn get_info_text(&self) -> Vec<String> {
+        let opens = match self.get_editor_target() {
+            EditorTarget::Workspace(file) => format!("workspace {}", file),
+            EditorTarget::Folders(folders) => folders.iter()
+                .map(|f| if f.is_empty() { "~" } else { f.as_str() })
+                .collect::<Vec<&str>>()
+                .join(", "),
+        };
+        let mut info = vec![format!("Opens: {}", opens)];
+        if !self.job_id.is_empty() {
+            info.push(format!("Job: {}", self.job_id));
+        }
+        info
+    }
+
+    fn get_field_errors(&self) -> Vec<(usize, String)> {
+        self.validate().iter().map(|e| (e.field(), e.to_string())).collect()
+    }
+
+    fn rename_references(&mut self, old_name: &str, new_name: &str) {
+        if self.extends == old_name {
+            self.extends = new_name.to_string();
+        }
+    }
+}
+
+/// The keys of the fields in the order of the info pane.
+const FIELD_KEYS: [&str; 13] = [
+    "preset_name", "account", "partition", "time", "working_directory",
+    "other_options", "additional_folders", "workspace_file",
+    "pre_spawn_commands", "post_start_commands", "container_image",
+    "container_binds", "extends"];
+
+/// Fields that belong to the preset itself and are never inherited.
+const OWN_FIELDS: [&str; 3] = ["preset_name", "extends", "job_id"];
+
+/// Name of the defaults block as the origin of inherited values.
+pub const DEFAULTS_NAME: &str = "defaults";
+
+/// Where the effective value of a field comes from.
+#[derive(Debug, PartialEq)]
+pub enum ValueSource {
+    /// The preset sets the value and no parent does.
+    Own,
+    /// The preset does not set the value, it is taken from the parent.
+    Inherited(String),
+    /// The preset sets a value that replaces the one of the parent.
+    Overridden(String),
+}
+
+/// A resolved table and, for every key, the name of the preset (or the
+/// defaults block) that set the value.
+type Resolved = (Table, HashMap<String, String>);
+
+/// Empty strings and lists count as unset and are inherited.
+pub fn is_unset(value: &Value) -> bool {
+    match value {
+        Value::String(s) => s.is_empty(),
+        Value::Array(a) => a.is_empty(),
+        _ => false,
+    }
+}
+
+/// Split a separated list from the editor into its trimmed,
+/// non-empty items.
+fn split_list(value: &str, separator: char) -> Vec<String> {
+    value.split(separator)
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Exit status of the allocation command if a pre-spawn command failed.
+const PRE_SPAWN_FAILED: i32 = 97;
+
+/// Fail with the error output of VS Code if it exited with an error,
+/// e.g. for an invalid uri or a broken installation.
+fn check_editor_output(output: &Output) -> Result<()> {
+    if output.status.success() {
+        return Ok(());
+    }
+    bail!("VS Code failed to open the session ({}): {}",
+          output.status, String::from_utf8_lossy(&output.stderr).trim())
+}
+
+/// A hook from the editor is kept as one script, such that `;`, loops
+/// and conditions inside it stay intact.
+fn parse_script(value: &str) -> Vec<String> {
+    match value.trim() {
+        "" => Vec::new(),
+        script => vec![script.to_string()],
+    }
+}
+
+/// Join the hook scripts such that the first failing one aborts the
+/// rest. Scripts with their own control operators are grouped, such
+/// that `&&` applies to the whole script.
+fn join_scripts(scripts: &[String]) -> String {
+    if scripts.len() < 2 {
+        return scripts.join("");
+    }
+    scripts.iter()
+        .map(|script| match script.contains([';', '&', '|', '\n']) {
+            false => script.clone(),
+            true if script.ends_with([';', '&']) => format!("{{ {} }}", script),
+            true => format!("{{ {}; }}", script),
+        })
+        .collect::<Vec<String>>()
+        .join(" && ")
 }
 
 impl Spawner {
@@ -107,31 +359,194 @@ impl Spawner {
             time: time.to_string(),
             working_directory: working_directory.to_string(),
             other_options: other_options.to_string(),
+            ..Default::default()
         }
     }
 
     // =======================================================================
-    //             MAIN FUNCTIONS
+    //             VALIDATION
     // =======================================================================
 
-    pub fn spawn(&self, session: &mut Session, cluster: &Cluster) -> Result<()> {
-        // get the node name
-        let mut node_name = self.get_node_name(session)?;
-        // if the node name is not found, spawn the job
-        if node_name.is_none() {
-            self.salloc(session, cluster)?;
-            node_name = self.get_node_name(session)?;
+    /// Check the fields of the preset. Inherited fields are only checked
+    /// on the resolved preset.
+    pub fn validate(&self) -> Vec<SpawnerError> {
+        let mut errors = Vec::new();
+        if self.preset_name.trim().is_empty() {
+            errors.push(SpawnerError::EmptyName);
         }
-        let node_name = node_name.unwrap(); 
-        // append teh node name to the ssh config file
-        self.add_cluster_to_ssh_config(&node_name, cluster)?;
+        // an empty time uses the default limit of the partition
+        if !self.time.is_empty() && parse_slurm_time(&self.time).is_none() {
+            errors.push(SpawnerError::InvalidTime(self.time.clone()));
+        }
+        errors
+    }
 
+    /// Check the resolved preset before it is spawned.
+    pub fn check(&self) -> Result<()> {
+        match self.validate().into_iter().next() {
+            Some(error) => Err(eyre!(error)),
+            None => Ok(()),
+        }
+    }
 
-        // clear the node from the known hosts file
-        // try to clear the node from the known hosts file
-        let _ = self.clear_known_host(&node_name);
-        self.spawn_vscode(&self.preset_name, session)?;
-        Ok(())
+    // =======================================================================
+    //             INHERITANCE
+    // =======================================================================
+
+    /// Get the preset with all unset fields filled in from the preset it
+    /// extends and from the defaults of the file.
+    pub fn resolve(&self, presets: &TomlList<Spawner>) -> Result<Spawner> {
+        let (table, _) = self.resolve_table(presets, &mut Vec::new())?;
+        Ok(Value::Table(table).try_into()?)
+    }
+
+    /// Get the source of every field in the order of the info pane.
+    pub fn value_sources(&self, presets: &TomlList<Spawner>) -> Result<Vec<ValueSource>> {
+        let (parent, origins) = self.resolve_parent(presets, &mut vec![
+            self.preset_name.clone()])?;
+        let own = Value::try_from(self)?;
+        Ok(FIELD_KEYS.iter().map(|key| {
+            let inherited = parent.get(*key).filter(|v| !is_unset(v));
+            let origin = origins.get(*key).cloned().unwrap_or_default();
+            match (own.get(*key), inherited) {
+                (_, None) => ValueSource::Own,
+                _ if OWN_FIELDS.contains(key) => ValueSource::Own,
+                (Some(value), Some(_)) if !is_unset(value) => {
+                    ValueSource::Overridden(origin)
+                },
+                _ => ValueSource::Inherited(origin),
+            }
+        }).collect())
+    }
+
+    fn resolve_table(&self, presets: &TomlList<Spawner>,
+                     chain: &mut Vec<String>) -> Result<Resolved> {
+        if chain.contains(&self.preset_name) {
+            bail!("Preset {} extends itself ({} -> {})",
+                  self.preset_name, chain.join(" -> "), self.preset_name);
+        }
+        chain.push(self.preset_name.clone());
+        let (parent, mut origins) = self.resolve_parent(presets, chain)?;
+        let mut table = match Value::try_from(self)? {
+            Value::Table(table) => table,
+            _ => unreachable!("a preset is always serialized as a table"),
+        };
+        for (key, value) in table.iter_mut() {
+            if OWN_FIELDS.contains(&key.as_str()) {
+                continue;
+            }
+            match parent.get(key) {
+                Some(inherited) if is_unset(value) => *value = inherited.clone(),
+                _ => { origins.insert(key.clone(), self.preset_name.clone()); },
+            }
+        }
+        Ok((table, origins))
+    }
+
+    /// Resolve the preset this one extends, or the defaults of the file.
+    fn resolve_parent(&self, presets: &TomlList<Spawner>,
+                      chain: &mut Vec<String>) -> Result<Resolved> {
+        if self.extends.is_empty() {
+            let origins = presets.defaults.keys()
+                .map(|key| (key.clone(), DEFAULTS_NAME.to_string()))
+                .collect();
+            return Ok((presets.defaults.clone(), origins));
+        }
+        let parent = presets.entry.iter()
+            .find(|p| p.preset_name == self.extends)
+            .ok_or_else(|| eyre!("Preset {} extends {}, which does not exist",
+                                 self.preset_name, self.extends))?;
+        parent.resolve_table(presets, chain)
+    }
+
+    /// Load the presets of all `.toml` files in a shared directory. They
+    /// are resolved within their file, such that they can be copied into
+    /// another list without their parents.
+    pub fn load_shared(dir: &str) -> Result<Vec<Spawner>> {
+        if dir.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .map_err(|e| eyre!("Cannot read the shared presets in {}: {}", dir, e))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+            .collect();
+        files.sort();
+        let mut shared = Vec::new();
+        for file in files {
+            let presets: TomlList<Spawner> = TomlList::load_read_only(&file)
+                .map_err(|e| eyre!("{}: {}", file.display(), e))?;
+            for preset in presets.entry.iter() {
+                shared.push(Spawner {
+                    extends: String::new(),
+                    job_id: String::new(),
+                    ..preset.resolve(&presets)?
+                });
+            }
+        }
+        Ok(shared)
+    }
+
+    // =======================================================================
+    //             MAIN FUNCTIONS
+    // =======================================================================
+
+    /// Spawn the session and return the job that it runs on.
+    /// A job that was allocated for this spawn is cancelled again if the
+    /// user aborts before the editor is launched.
+    pub fn spawn(&self, session: &mut Session, cluster: &Cluster,
+                 progress: &Progress) -> Result<SpawnResult> {
+        self.check()?;
+        progress.stage(Stage::PreparingJob)?;
+        // make sure the container can be started before allocating
+        let container_runtime = self.check_container(session, cluster)?;
+        // reuse the running job, or allocate a new one
+        let (job, allocated) = match self.find_job(session, cluster)? {
+            Some(job) => (job, false),
+            None => {
+                progress.stage(Stage::WaitingForAllocation)?;
+                let job_id = self.salloc(session, cluster, progress)?;
+                let job = Job::find_by_id(session, cluster, &job_id)?
+                    .ok_or_else(|| eyre!("Job {} is not in the queue", job_id));
+                (self.cancel_if_aborted(session, cluster, &job_id, job, progress)?, true)
+            }
+        };
+        let node_name = job.node.clone();
+        let mut prepare = || -> Result<()> {
+            progress.stage(Stage::PreparingNode)?;
+            // append the node name to the ssh config file
+            self.add_cluster_to_ssh_config(
+                &node_name, cluster, container_runtime.as_deref())?;
+            self.run_post_start_commands(session, cluster, &node_name, progress)?;
+            // try to clear the node from the known hosts file
+            let _ = self.clear_known_host(&node_name);
+            if cluster.preseed_vscode_server {
+                vscode_server::preseed(session, cluster, &node_name, progress)?;
+            }
+            progress.stage(Stage::LaunchingEditor)
+        };
+        let prepared = prepare();
+        if allocated {
+            self.cancel_if_aborted(session, cluster, &job.id, prepared, progress)?;
+        } else {
+            prepared?;
+        }
+        let uris = self.spawn_vscode(&self.preset_name, session)?;
+        Ok(SpawnResult {
+            job,
+            alias: format!("cr-{}", self.preset_name),
+            uris,
+        })
+    }
+
+    /// Cancel the newly allocated job if the user aborted the spawn.
+    fn cancel_if_aborted<T>(&self, session: &mut Session, cluster: &Cluster,
+                            job_id: &str, result: Result<T>,
+                            progress: &Progress) -> Result<T> {
+        if progress.is_aborted() {
+            let _ = cluster.execute_and_capture(session, &format!("scancel {}", job_id));
+        }
+        result
     }
 
     pub fn get_spawn_command(&self) -> String {
@@ -153,79 +568,146 @@ impl Spawner {
             command.push_str(&self.time);
         }
         if !self.other_options.is_empty() {
-            command.push_str(" ");
+            command.push(' ');
             command.push_str(&self.other_options);
         }
         command.push_str(" --no-shell; exit");
         command
     }
 
-    pub fn spawn_vscode(&self, node_alias: &str, session: &mut Session) -> Result<()> {
-        // get the home directory if the working directory is not set
-        let mut code_wd = self.working_directory.clone();
-        if self.working_directory.is_empty() {
-            let command = "echo $HOME";
-            let mut channel = session.channel_session()?;
-            channel.exec(&command)?;
-            let mut output = String::new();
-            channel.read_to_string(&mut output)?;
-            code_wd = output.trim().to_string();
+    /// Get the workspace file or the list of folders that should be opened.
+    /// A workspace file takes precedence over the folders.
+    pub fn get_editor_target(&self) -> EditorTarget {
+        if !self.workspace_file.is_empty() {
+            return EditorTarget::Workspace(self.workspace_file.clone());
         }
+        let mut folders = vec![self.working_directory.clone()];
+        folders.extend(self.additional_folders.iter().cloned());
+        EditorTarget::Folders(folders)
+    }
+
+    /// Get the preset with `~` and variables in the remote paths expanded
+    /// with the environment of the cluster.
+    pub fn expand_paths(&self, environment: &HashMap<String, String>) -> Spawner {
+        let expand = |path: &String| expand_remote(path, environment);
+        Spawner {
+            working_directory: expand(&self.working_directory),
+            additional_folders: self.additional_folders.iter().map(expand).collect(),
+            workspace_file: expand(&self.workspace_file),
+            ..self.clone()
+        }
+    }
 
-        let remote_argument = format!(
-            "vscode-remote://ssh-remote+cr-{}/{}", node_alias, code_wd);
-        Command::new("code")
-            .arg("--folder-uri").arg(remote_argument)
+    /// Get the arguments for the `code` command. Relative paths (and the
+    /// empty working directory) are resolved against the remote home.
+    pub fn get_editor_arguments(&self, node_alias: &str, home: &str) -> Vec<String> {
+        let remote_uri = |path: &str| {
+            let path = if path.starts_with('/') {
+                path.to_string()
+            } else if path.is_empty() {
+                home.to_string()
+            } else {
+                format!("{}/{}", home.trim_end_matches('/'), path)
+            };
+            format!("vscode-remote://ssh-remote+cr-{}/{}",
+                    node_alias, path.trim_start_matches('/'))
+        };
+        match self.get_editor_target() {
+            EditorTarget::Workspace(file) => {
+                vec!["--file-uri".to_string(), remote_uri(&file)]
+            },
+            EditorTarget::Folders(folders) => folders.iter()
+                .flat_map(|f| ["--folder-uri".to_string(), remote_uri(f)])
+                .collect(),
+        }
+    }
+
+    /// Launch VS Code and return the uris of the opened folders or workspace.
+    pub fn spawn_vscode(&self, node_alias: &str, session: &mut Session) -> Result<Vec<String>> {
+        // get the environment to expand the paths and to resolve
+        // relative paths against the home directory
+        let environment = read_remote_environment(session)?;
+        let home = environment.get("HOME").cloned().unwrap_or_default();
+
+        let arguments = self.expand_paths(&environment)
+            .get_editor_arguments(node_alias, &home);
+        let output = Command::new("code")
+            .args(&arguments)
             .output()
-            .expect("Failed to start Visual Studio Code");
-        Ok(())
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => eyre!(
+                    "Could not find the `code` command. Please install VS Code \
+                     and make sure that `code` is in the PATH."),
+                _ => eyre!("Could not launch VS Code: {}", e),
+            })?;
+        check_editor_output(&output)?;
+        let uris = arguments.into_iter()
+            .filter(|arg| !arg.starts_with("--"))
+            .collect();
+        Ok(uris)
     }
 
     // =======================================================================
     //            FILE OPERATIONS
     // =======================================================================
 
-    pub fn format_config_entry(&self, node_name: &str, cluster: &Cluster) -> String {
+    /// Record the job id in the preset file. The file is modified under
+    /// the lock, as a spawn may take long and other instances may change
+    /// the file meanwhile.
+    pub fn record_job(filename: &str, preset_name: &str, job_id: &str) -> Result<()> {
+        TomlList::<Spawner>::modify(filename, |presets| {
+            if let Some(preset) = presets.entry.iter_mut()
+                .find(|p| p.preset_name == preset_name) {
+                preset.job_id = job_id.to_string();
+            }
+        })
+    }
+
+    /// Get the command that enters the container on the compute node.
+    /// The arguments are quoted for the remote shell, and `%` is escaped
+    /// since ssh expands tokens in `RemoteCommand`.
+    pub fn get_container_command(&self, container_runtime: &str) -> String {
+        let mut command = format!("{} shell", container_runtime);
+        if !self.container_binds.is_empty() {
+            command.push_str(&format!(" --bind {}", shell_quote(&self.container_binds.join(","))));
+        }
+        command.push_str(&format!(" {}", quote_path(&self.container_image)));
+        command.replace('%', "%%")
+    }
+
+    pub fn format_config_entry(
+        &self, node_name: &str, cluster: &Cluster,
+        container_runtime: Option<&str>) -> String {
         let mut entry = String::new();
         entry.push_str(&format!("# code-remote: start {}\n", self.preset_name));
         entry.push_str(format!("Host cr-{}\n", self.preset_name).as_str());
         entry.push_str(format!("    HostName {}\n", node_name).as_str());
         entry.push_str(format!("    User {}\n", cluster.user).as_str());
         if !cluster.identity_file.is_empty() {
-            entry.push_str(format!("    IdentityFile {}\n", cluster.identity_file).as_str());
+            entry.push_str(format!("    IdentityFile {}\n", cluster.identity_path()).as_str());
+        }
+        if !cluster.certificate_file.is_empty() {
+            entry.push_str(format!("    CertificateFile {}\n", cluster.certificate_path()).as_str());
         }
         entry.push_str(format!("    ProxyJump cr-{}\n", cluster.name).as_str());
+        if let Some(runtime) = container_runtime {
+            entry.push_str(&format!(
+                "    RemoteCommand {}\n", self.get_container_command(runtime)));
+            entry.push_str("    RequestTTY yes\n");
+        }
         entry.push_str(&format!("# code-remote: end {}", self.preset_name));
         entry
     }
 
-    pub fn add_cluster_to_ssh_config(&self, node_name: &str, cluster: &Cluster) -> Result<()> {
+    pub fn add_cluster_to_ssh_config(
+        &self, node_name: &str, cluster: &Cluster,
+        container_runtime: Option<&str>) -> Result<()> {
         // Read the contents of the .ssh/config file
-        let home = std::env::var("HOME")?;
-        let config_file_path = format!("{}/.ssh/config", home);
-        let config_content = fs::read_to_string(&config_file_path)?;
-
-        // Define the regex pattern to match the start and end of the code remote entry
-        let pattern = format!(r"(?ms)^# code-remote: start {}\n.*?# code-remote: end {}\s*"
-                              , self.preset_name, self.preset_name);
-
-        // Create a regex object
-        let re = Regex::new(&pattern)?;
-
-        // Replace the code remote entry with an empty string
-        let modified_content = re.replace_all(&config_content, "").to_string();
-
-        // Write the modified content back to the file
+        let (config_file_path, config_content) = read_ssh_config()?;
+        let entry = self.format_config_entry(node_name, cluster, container_runtime);
+        let modified_content = Cluster::replace_config_entry(
+            &config_content, &self.preset_name, &entry)?;
         fs::write(&config_file_path, modified_content)?;
-        
-        let entry = self.format_config_entry(node_name, cluster);
-        let mut file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(&config_file_path)?;
-        writeln!(file, "{}", entry)?;
         Ok(())
     }
 
@@ -235,7 +717,7 @@ impl Spawner {
         if std::path::Path::new(&known_hosts_path).exists() {
             // don't print the output of the command
             Command::new("ssh-keygen")
-                .args(&["-f", &known_hosts_path, "-R", &node_alias])
+                .args(["-f", &known_hosts_path, "-R", node_alias])
                 .output()?;
         }
         Ok(())
@@ -245,28 +727,396 @@ impl Spawner {
     //             SSH OPERATIONS
     // =======================================================================
 
-    pub fn get_node_name(&self, session: &mut Session) -> Result<Option<String>> {
+    /// Find the running job of this preset. A recorded job id takes
+    /// precedence over matching by the job name.
+    pub fn find_job(&self, session: &mut Session, cluster: &Cluster) -> Result<Option<Job>> {
+        if !self.job_id.is_empty() {
+            let job = Job::find_by_id(session, cluster, &self.job_id)?;
+            return Ok(job.filter(|job| job.is_running()));
+        }
+        let mut jobs = Job::find_by_name(session, cluster, &self.preset_name)?;
+        jobs.retain(|job| job.is_running());
+        if jobs.len() > 1 {
+            bail!("Found {} running jobs named {}.\n\
+                   Press 'a' to attach to one of them.",
+                  jobs.len(), self.preset_name);
+        }
+        Ok(jobs.pop())
+    }
 
-        // get the node name
-        let command = format!("squeue -u $USER --name {} --noheader --format=%N", self.preset_name);
-        let mut channel = session.channel_session()?;
-        channel.exec(&command)?;
-        // read the output
-        let mut output = String::new();
-        channel.read_to_string(&mut output)?;
+    /// Cancel the running job of this preset and return its id.
+    pub fn cancel(&self, session: &mut Session, cluster: &Cluster) -> Result<String> {
+        let job = self.find_job(session, cluster)?
+            .ok_or_else(|| eyre!("No running job found for {}", self.preset_name))?;
+        let (exit_status, _) = cluster.execute_and_capture(
+            session, &format!("scancel {}", job.id))?;
+        if exit_status != 0 {
+            return Err(ConnectionError::scheduler(
+                "scancel", eyre!("exit status {} for job {}", exit_status, job.id)));
+        }
+        Ok(job.id)
+    }
 
-        // If the output is empty, return None
-        if output.is_empty() {
+    /// Allocate the job and return its id. If the user aborts while the
+    /// job is pending, the pending allocation is cancelled.
+    pub fn salloc(&self, session: &mut Session, cluster: &Cluster,
+                  progress: &Progress) -> Result<String> {
+        let command = self.get_allocation_command();
+        let output = match cluster.execute_and_forward(session, &command, progress) {
+            Ok(output) => output,
+            Err(e) => {
+                if progress.is_aborted() {
+                    let _ = cluster.execute_and_capture(
+                        session, &self.get_cancel_pending_command(cluster));
+                }
+                if e.downcast_ref::<CommandFailed>()
+                    .is_some_and(|failed| failed.exit_status == PRE_SPAWN_FAILED) {
+                    return Err(eyre!("Pre-spawn commands failed: {}", e));
+                }
+                return Err(ConnectionError::scheduler("salloc", e));
+            },
+        };
+        Job::parse_salloc_output(&output).ok_or_else(|| ConnectionError::scheduler(
+            "salloc", eyre!("Could not find the job id in the salloc output")))
+    }
+
+    /// Get the command that cancels the pending allocation of this preset.
+    pub fn get_cancel_pending_command(&self, cluster: &Cluster) -> String {
+        format!("scancel --state=PENDING --user={} --name={}",
+                shell_quote(&cluster.user), shell_quote(&self.preset_name))
+    }
+
+    /// Get the command that allocates the job. The pre-spawn commands run
+    /// in the same login shell before `salloc`, such that the environment
+    /// they set up (e.g. `module load` or `SALLOC_ACCOUNT`) applies to it.
+    /// The first failing script aborts the allocation.
+    pub fn get_allocation_command(&self) -> String {
+        if self.pre_spawn_commands.is_empty() {
+            return self.get_spawn_command();
+        }
+        let script = format!("{{ {}\n}} || exit {}\n{}",
+                             join_scripts(&self.pre_spawn_commands), PRE_SPAWN_FAILED,
+                             self.get_spawn_command());
+        format!("bash -lc {}", shell_quote(&script))
+    }
+
+    /// Get the command that runs the post-start commands on the compute
+    /// node by connecting to it from the login node.
+    pub fn get_post_start_command(&self, node_name: &str) -> String {
+        let script = format!(
+            "bash -lc {}", shell_quote(&join_scripts(&self.post_start_commands)));
+        format!("ssh -o BatchMode=yes -o ConnectTimeout=30 {} {}",
+                node_name, shell_quote(&script))
+    }
+
+    /// Check that the container image exists on the cluster and find the
+    /// container runtime. Returns `None` if no container is configured.
+    pub fn check_container(&self, session: &mut Session, cluster: &Cluster) -> Result<Option<String>> {
+        if self.container_image.is_empty() {
             return Ok(None);
         }
+        let command = format!("test -r {}", quote_path(&self.container_image));
+        let (exit_status, _) = cluster.execute_and_capture(session, &command)?;
+        if exit_status != 0 {
+            bail!("Container image {} does not exist on the cluster",
+                  self.container_image);
+        }
+        let command = "command -v apptainer || command -v singularity";
+        let (_, output) = cluster.execute_and_capture(session, command)?;
+        let runtime = output.lines().next()
+            .and_then(|path| path.trim().rsplit('/').next())
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| eyre!("Neither apptainer nor singularity is installed on the cluster"))?;
+        Ok(Some(runtime.to_string()))
+    }
+
+    /// Run the post-start commands on the compute node.
+    pub fn run_post_start_commands(
+        &self, session: &mut Session, cluster: &Cluster, node_name: &str,
+        progress: &Progress) -> Result<()> {
+        if self.post_start_commands.is_empty() {
+            return Ok(());
+        }
+        let command = self.get_post_start_command(node_name);
+        cluster.execute_and_forward(session, &command, progress)
+            .map(|_| ())
+            .map_err(|e| eyre!("Post-start commands failed: {}", e))
+    }
 
-        Ok(Some(output.trim().to_string()))
+}
 
+/// Quote a string for the remote POSIX shell.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Quote a path for the remote POSIX shell, but keep a leading `~/`
+/// unquoted such that it is still expanded to the home directory.
+pub fn quote_path(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some(rest) => format!("~/{}", shell_quote(rest)),
+        None => shell_quote(path),
+    }
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::double_column_menu::entry::EntryChange;
+
+    #[test]
+    fn test_editor_arguments_folders() {
+        let spawner = Spawner {
+            additional_folders: vec![
+                "/scratch/data".to_string(), "config".to_string()],
+            ..Default::default()
+        };
+        let args = spawner.get_editor_arguments("preset", "/home/user");
+        assert_eq!(args, vec![
+            "--folder-uri", "vscode-remote://ssh-remote+cr-preset/home/user",
+            "--folder-uri", "vscode-remote://ssh-remote+cr-preset/scratch/data",
+            "--folder-uri", "vscode-remote://ssh-remote+cr-preset/home/user/config",
+        ]);
     }
 
-    pub fn salloc(&self, session: &mut Session, cluster: &Cluster) -> Result<()> {
-        let command = self.get_spawn_command();
-        Ok(cluster.execute_and_forward(session, &command)?)
+    #[test]
+    fn test_editor_arguments_workspace() {
+        let spawner = Spawner {
+            working_directory: "/work/project".to_string(),
+            workspace_file: "/work/project/all.code-workspace".to_string(),
+            ..Default::default()
+        };
+        let args = spawner.get_editor_arguments("preset", "/home/user");
+        assert_eq!(args, vec![
+            "--file-uri",
+            "vscode-remote://ssh-remote+cr-preset/work/project/all.code-workspace",
+        ]);
     }
 
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("echo hi"), "'echo hi'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn test_hook_commands() {
+        let mut spawner = Spawner::default();
+        spawner.set_value_from_index(8, " module purge; module load git ");
+        spawner.set_value_from_index(9, "echo started");
+        // the script is kept as it is, including the `;`
+        assert_eq!(spawner.pre_spawn_commands, vec!["module purge; module load git"]);
+        assert_eq!(spawner.get_value_from_index(8), "module purge; module load git");
+        // the hooks run before salloc in the same shell
+        spawner.partition = "compute".to_string();
+        assert_eq!(spawner.get_allocation_command(),
+                   "bash -lc '{ module purge; module load git\n} || exit 97\n\
+                    salloc -p compute --no-shell; exit'");
+        assert_eq!(
+            spawner.get_post_start_command("node1"),
+            r"ssh -o BatchMode=yes -o ConnectTimeout=30 node1 'bash -lc '\''echo started'\'''");
+        spawner.set_value_from_index(9, "");
+        assert!(spawner.post_start_commands.is_empty());
+    }
+
+    #[test]
+    fn test_check_editor_output() {
+        let output = Command::new("sh").args(["-c", "exit 0"]).output().unwrap();
+        assert!(check_editor_output(&output).is_ok());
+        let output = Command::new("sh").args(["-c", "echo invalid uri >&2; exit 1"])
+            .output().unwrap();
+        let error = check_editor_output(&output).unwrap_err().to_string();
+        assert!(error.contains("exit status: 1"), "{}", error);
+        assert!(error.ends_with(": invalid uri"), "{}", error);
+    }
+
+    #[test]
+    fn test_join_scripts() {
+        // several scripts from a preset file
+        let scripts = vec![
+            "module load git".to_string(),
+            "for f in a b; do touch $f; done".to_string(),
+            "sleep 1 &".to_string(),
+        ];
+        assert_eq!(join_scripts(&scripts),
+                   "module load git && { for f in a b; do touch $f; done; } && { sleep 1 & }");
+    }
+
+    #[test]
+    fn test_cancel_pending_command() {
+        let spawner = Spawner::new("gpu", "", "gpu", "", "", "");
+        let cluster = Cluster::new("levante", "levante.dkrz.de", "u301533", "");
+        assert_eq!(spawner.get_cancel_pending_command(&cluster),
+                   "scancel --state=PENDING --user='u301533' --name='gpu'");
+    }
+
+    #[test]
+    fn test_quote_path() {
+        assert_eq!(quote_path("/img/my image.sif"), "'/img/my image.sif'");
+        assert_eq!(quote_path("~/img.sif"), "~/'img.sif'");
+    }
+
+    #[test]
+    fn test_container_config_entry() {
+        let cluster = Cluster::new("levante", "levante.dkrz.de", "user", "");
+        let spawner = Spawner {
+            preset_name: "gpu".to_string(),
+            container_image: "/sw/images/torch.sif".to_string(),
+            container_binds: vec!["/scratch".to_string(), "/work:/mnt".to_string()],
+            ..Default::default()
+        };
+        let entry = spawner.format_config_entry("node1", &cluster, Some("apptainer"));
+        assert!(entry.contains(
+            "    RemoteCommand apptainer shell --bind '/scratch,/work:/mnt' '/sw/images/torch.sif'\n"));
+        assert!(entry.contains("    RequestTTY yes\n"));
+        let entry = spawner.format_config_entry("node1", &cluster, None);
+        assert!(!entry.contains("RemoteCommand"));
+        let spawner = Spawner {
+            container_image: "~/my images/100%.sif".to_string(),
+            container_binds: vec!["/scratch/it's".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(spawner.get_container_command("singularity"),
+                   r"singularity shell --bind '/scratch/it'\''s' ~/'my images/100%%.sif'");
+    }
+
+    #[test]
+    fn test_additional_folders_from_editor() {
+        let mut spawner = Spawner::default();
+        spawner.set_value_from_index(6, " /a, ,/b ");
+        assert_eq!(spawner.additional_folders, vec!["/a", "/b"]);
+        assert_eq!(spawner.get_value_from_index(6), "/a, /b");
+    }
+
+    fn create_inheriting_presets() -> TomlList<Spawner> {
+        let mut presets = TomlList::new();
+        presets.defaults.insert("account".to_string(), "acc".into());
+        presets.defaults.insert("other_options".to_string(), "--mem=0".into());
+        presets.push(Spawner::new("base", "", "compute", "08:00:00", "/work", ""));
+        presets.push(Spawner {
+            extends: "base".to_string(),
+            ..Spawner::new("gpu", "", "gpu", "", "", "--gpus=1")
+        });
+        presets
+    }
+
+    #[test]
+    fn test_parse_slurm_time() {
+        assert_eq!(parse_slurm_time("30"), Some(1800));
+        assert_eq!(parse_slurm_time("90:30"), Some(5430));
+        assert_eq!(parse_slurm_time("02:00:00"), Some(7200));
+        assert_eq!(parse_slurm_time("1-12"), Some(129600));
+        assert_eq!(parse_slurm_time("1-0:30"), Some(88200));
+        assert_eq!(parse_slurm_time("2-00:00:01"), Some(172801));
+        assert_eq!(parse_slurm_time("UNLIMITED"), Some(u64::MAX));
+        for invalid in ["", "1h", "1:2:3:4", "01:60:00", "1-24", "-5", "1-", "1:-2"] {
+            assert_eq!(parse_slurm_time(invalid), None, "{}", invalid);
+        }
+        // too large values are invalid instead of overflowing
+        for overflow in ["999999999999999999", "999999999999999999-0",
+                         "5124095576030432:00:00", "99999999999999999999"] {
+            assert_eq!(parse_slurm_time(overflow), None, "{}", overflow);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut spawner = Spawner::new("", "", "", "2 hours", "", "");
+        assert_eq!(spawner.validate(), vec![
+            SpawnerError::EmptyName, SpawnerError::InvalidTime("2 hours".to_string())]);
+        assert_eq!(spawner.get_field_errors()[1].0, 3);
+        spawner.preset_name = "gpu".to_string();
+        spawner.time = String::new();
+        assert!(spawner.check().is_ok());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let presets = create_inheriting_presets();
+        let gpu = presets.get(1).unwrap().resolve(&presets).unwrap();
+        assert_eq!(gpu.preset_name, "gpu");
+        assert_eq!(gpu.account, "acc");
+        assert_eq!(gpu.partition, "gpu");
+        assert_eq!(gpu.time, "08:00:00");
+        assert_eq!(gpu.working_directory, "/work");
+        assert_eq!(gpu.other_options, "--gpus=1");
+        assert_eq!(gpu.extends, "base");
+    }
+
+    #[test]
+    fn test_value_sources() {
+        let presets = create_inheriting_presets();
+        let sources = presets.get(1).unwrap().value_sources(&presets).unwrap();
+        assert_eq!(sources[0], ValueSource::Own);
+        assert_eq!(sources[1], ValueSource::Inherited("defaults".to_string()));
+        assert_eq!(sources[2], ValueSource::Overridden("base".to_string()));
+        assert_eq!(sources[3], ValueSource::Inherited("base".to_string()));
+        assert_eq!(sources[5], ValueSource::Overridden("defaults".to_string()));
+        assert_eq!(sources[10], ValueSource::Own);
+    }
+
+    #[test]
+    fn test_rename_keeps_children() {
+        let mut presets = create_inheriting_presets();
+        let change = EntryChange::Set {
+            name: "base".to_string(), index: 0, value: "common".to_string() };
+        assert_eq!(change.apply(&mut presets).unwrap(), "common");
+        assert_eq!(presets.get(1).unwrap().extends, "common");
+        assert_eq!(presets.get(1).unwrap().resolve(&presets).unwrap().time, "08:00:00");
+        // a new preset inherits the time as well
+        let new = Spawner {
+            preset_name: "new".to_string(),
+            extends: "gpu".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(new.resolve(&presets).unwrap().time, "08:00:00");
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let mut presets = create_inheriting_presets();
+        presets.get_mut(0).unwrap().extends = "gpu".to_string();
+        let error = presets.get(1).unwrap().resolve(&presets).unwrap_err();
+        assert!(error.to_string().contains("extends itself"));
+        presets.get_mut(0).unwrap().extends = "missing".to_string();
+        let error = presets.get(0).unwrap().resolve(&presets).unwrap_err();
+        assert!(error.to_string().contains("does not exist"));
+    }
+
+    #[test]
+    fn test_load_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        create_inheriting_presets().save_to(dir.path(), "team").unwrap();
+        std::fs::write(dir.path().join("README.md"), "not a preset file").unwrap();
+        let shared = Spawner::load_shared(dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(shared.len(), 2);
+        assert_eq!(shared[1].preset_name, "gpu");
+        assert_eq!(shared[1].account, "acc");
+        assert_eq!(shared[1].time, "08:00:00");
+        assert!(shared[1].extends.is_empty());
+        assert!(Spawner::load_shared("").unwrap().is_empty());
+        assert!(Spawner::load_shared("/does/not/exist").is_err());
+    }
+
+    #[test]
+    fn test_migrate_version_0() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("levante.toml"), "\
+            [[entry]]\n\
+            preset_name = \"gpu\"\n\
+            account = \"acc\"\n\
+            partition = \"gpu\"\n\
+            time = \"01:00:00\"\n\
+            working_directory = \"\"\n\
+            other_options = \"\"\n").unwrap();
+        let presets: TomlList<Spawner> =
+            TomlList::load_from(dir.path(), "levante").unwrap();
+        assert_eq!(presets.migrated_from, Some(0));
+        assert_eq!(presets.get(0).unwrap(), &Spawner::new(
+            "gpu", "acc", "gpu", "01:00:00", "", ""));
+    }
 }
@@ -1,18 +1,26 @@
+use std::collections::HashMap;
 use tui_textarea::{TextArea};
+use crossterm::event::{KeyCode, KeyEvent};
 
-use crate::menus::spawner::Spawner;
+use crate::menus::spawner::{Spawner, ValueSource};
 use crate::double_column_menu::{
+    entry::Entry,
     toml_list::TomlList,
     counter::Counter,
     double_column_menu::{DoubleColumnMenu, Focus, InputMode}};
 
 use crate::app::{Action};
 
-const MAX_INFO_COUNTER: u32 = 6;
+const MAX_INFO_COUNTER: u32 = 13;
 
 #[derive(Debug)]
 pub struct SpawnerMenu {
-    pub cluster_name: String,
+    /// The preset file of the selected cluster.
+    pub preset_file: String,
+    /// The read-only presets of the shared directory of the cluster.
+    pub shared: Vec<Spawner>,
+    /// The environment of the cluster, to show the expanded paths.
+    pub environment: HashMap<String, String>,
     pub list_counter: Counter,
     pub info_counter: Counter,
     entries: TomlList<Spawner>,
@@ -25,10 +33,12 @@ impl Default for SpawnerMenu {
     fn default() -> Self {
         let entries: TomlList<Spawner> = TomlList::new();
         SpawnerMenu {
-            cluster_name: "cluster".to_string(),
+            preset_file: String::new(),
+            shared: Vec::new(),
+            environment: HashMap::new(),
             list_counter: Counter::new(1),
             info_counter: Counter::new(MAX_INFO_COUNTER),
-            entries: entries,
+            entries,
             focus: Focus::default(),
             input_mode: InputMode::default(),
             text_area: TextArea::default(),
@@ -62,7 +72,7 @@ impl DoubleColumnMenu<Spawner> for SpawnerMenu {
     }
 
     fn get_filename(&self) -> &str {
-        self.cluster_name.as_str()
+        self.preset_file.as_str()
     }
 
     fn get_titlename(&self) -> &str {
@@ -96,6 +106,68 @@ impl DoubleColumnMenu<Spawner> for SpawnerMenu {
     fn action_left(&mut self, action: &mut Action) {
         *action = Action::OpenClusterMenu;
     }
+
+    fn input_other(&mut self, action: &mut Action, key_event: KeyEvent) {
+        if self.is_new_entry() || self.is_shared_entry() {
+            return;
+        }
+        if let KeyCode::Char('a') = key_event.code {
+            *action = Action::OpenJobList;
+        }
+    }
+
+    fn get_shared_entries(&self) -> &[Spawner] {
+        &self.shared
+    }
+
+    /// Show the inherited values and mark where they come from.
+    fn get_display_values(&self) -> Vec<String> {
+        let Ok(entry) = self.get_entry() else {
+            return Vec::new();
+        };
+        // shared presets are resolved when they are loaded
+        if self.is_shared_entry() {
+            return entry.get_entry_values();
+        }
+        let (resolved, sources) = match (
+            entry.resolve(&self.entries), entry.value_sources(&self.entries)) {
+            (Ok(resolved), Ok(sources)) => (resolved, sources),
+            _ => return entry.get_entry_values(),
+        };
+        resolved.get_entry_values().into_iter().zip(sources)
+            .map(|(value, source)| match source {
+                ValueSource::Own => value,
+                ValueSource::Inherited(from) => format!("{}  (from {})", value, from),
+                ValueSource::Overridden(from) => format!("{}  (overrides {})", value, from),
+            })
+            .collect()
+    }
+
+    fn get_display_info_text(&self) -> Vec<String> {
+        let Ok(entry) = self.get_entry() else {
+            return Vec::new();
+        };
+        if self.is_shared_entry() {
+            let mut info = entry.expand_paths(&self.environment).get_info_text();
+            info.push("Shared preset (read-only)".to_string());
+            return info;
+        }
+        match entry.resolve(&self.entries) {
+            Ok(resolved) => resolved.expand_paths(&self.environment).get_info_text(),
+            Err(e) => vec![format!("Error: {}", e)],
+        }
+    }
+
+    /// Check the resolved preset, such that inherited values are checked.
+    fn get_display_errors(&self) -> Vec<(usize, String)> {
+        let Ok(entry) = self.get_entry() else {
+            return Vec::new();
+        };
+        match entry.resolve(&self.entries) {
+            Ok(resolved) => resolved.get_field_errors(),
+            Err(_) => entry.get_field_errors(),
+        }
+    }
 }
 
 // =======================================================================
@@ -114,4 +186,45 @@ mod tests {
         assert_eq!(spawner_menu.entries.len(), 1);
     }
 
+    #[test]
+    fn test_shared_entries() {
+        let mut spawner_menu = SpawnerMenu::default();
+        spawner_menu.add_entry(Spawner::new("gpu", "", "", "", "", ""));
+        spawner_menu.shared = vec![Spawner::new("gpu", "acc", "gpu", "", "", "")];
+        spawner_menu.update_list_length();
+        assert_eq!(spawner_menu.get_entry_names(),
+                   vec!["gpu", "Create New", "[shared] gpu"]);
+        spawner_menu.list_counter.set_value(2);
+        assert!(spawner_menu.is_shared_entry());
+        assert_eq!(spawner_menu.get_entry().unwrap().account, "acc");
+        assert!(spawner_menu.get_entry_mut().is_err());
+        // "Create New" is not the last name if there are shared entries
+        spawner_menu.list_counter.set_value(1);
+        assert_eq!(spawner_menu.check_entry_name("gpu"), "gpu(1)");
+        assert_eq!(spawner_menu.check_entry_name("[shared] gpu"), "[shared] gpu(1)");
+    }
+
+    #[test]
+    fn test_expanded_paths() {
+        let mut spawner_menu = SpawnerMenu::default();
+        spawner_menu.add_entry(Spawner::new("cpu", "", "", "", "$WORK/project", ""));
+        assert_eq!(spawner_menu.get_display_info_text()[0], "Opens: $WORK/project");
+        spawner_menu.environment.insert("WORK".to_string(), "/work/ab1234".to_string());
+        assert_eq!(spawner_menu.get_display_info_text()[0], "Opens: /work/ab1234/project");
+        // the preset keeps the unexpanded path
+        assert_eq!(spawner_menu.get_display_values()[4], "$WORK/project");
+    }
+
+    #[test]
+    fn test_inherited_errors() {
+        let mut spawner_menu = SpawnerMenu::default();
+        spawner_menu.entries.defaults.insert("time".to_string(), "2h".into());
+        spawner_menu.add_entry(Spawner::new("gpu", "", "", "", "", ""));
+        let errors = spawner_menu.get_display_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 3);
+        spawner_menu.entries.defaults.insert("time".to_string(), "2:00:00".into());
+        assert!(spawner_menu.get_display_errors().is_empty());
+    }
+
 }
@@ -0,0 +1,129 @@
+use std::{fs, process::Command};
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone};
+use color_eyre::eyre::{bail, Result};
+use regex::Regex;
+
+/// Warn this many hours before a certificate expires.
+pub const CERTIFICATE_WARNING_HOURS: i64 = 2;
+
+/// Check whether a public key of the ssh agent is a certificate. The key
+/// blob starts with the length-prefixed key type, e.g.
+/// `ssh-ed25519-cert-v01@openssh.com`.
+pub fn is_certificate_blob(blob: &[u8]) -> bool {
+    match blob {
+        [a, b, c, d, rest @ ..] => {
+            let length = u32::from_be_bytes([*a, *b, *c, *d]) as usize;
+            rest.get(..length).is_some_and(|key_type| key_type.ends_with(b"-cert-v01@openssh.com"))
+        },
+        _ => false,
+    }
+}
+
+/// Check whether the identity file is a FIDO security key (`sk-*` key
+/// types), by the type in its public key file `<identity file>.pub`.
+pub fn is_security_key(identity_path: &str) -> bool {
+    fs::read_to_string(format!("{}.pub", identity_path))
+        .is_ok_and(|public_key| public_key.trim_start().starts_with("sk-"))
+}
+
+/// Read the end of the validity of a certificate with `ssh-keygen -L`.
+/// Certificates that are valid forever have no end.
+pub fn certificate_expiry(path: &str) -> Result<Option<DateTime<Local>>> {
+    let output = Command::new("ssh-keygen").args(["-L", "-f", path]).output()?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    parse_valid_until(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parse the end of the validity from the output of `ssh-keygen -L`, e.g.
+/// `Valid: from 2024-05-02T09:00:00 to 2024-05-02T21:00:00`. The times
+/// are in the local time zone.
+pub fn parse_valid_until(output: &str) -> Result<Option<DateTime<Local>>> {
+    let re = Regex::new(r"(?m)^\s*Valid: (.*)$")?;
+    let Some(validity) = re.captures(output).map(|c| c[1].trim().to_string()) else {
+        bail!("The file is not a certificate");
+    };
+    let end = match validity.rsplit_once(" to ").or_else(|| validity.split_once("before ")) {
+        Some((_, end)) => end.trim(),
+        // valid forever, or only a start
+        None => return Ok(None),
+    };
+    let time = NaiveDateTime::parse_from_str(end, "%Y-%m-%dT%H:%M:%S")?;
+    Ok(Local.from_local_datetime(&time).earliest())
+}
+
+/// A warning if the certificate expired or expires soon.
+pub fn format_expiry_warning(path: &str, valid_until: DateTime<Local>,
+                             now: DateTime<Local>) -> Option<String> {
+    let left = valid_until - now;
+    if left <= Duration::zero() {
+        return Some(format!(
+            "The certificate {} expired at {}. Please renew it.",
+            path, valid_until.format("%Y-%m-%d %H:%M")));
+    }
+    if left < Duration::hours(CERTIFICATE_WARNING_HOURS) {
+        return Some(format!(
+            "The certificate {} expires at {} (in {} min). Please renew it soon.",
+            path, valid_until.format("%H:%M"), left.num_minutes()));
+    }
+    None
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(key_type: &str) -> Vec<u8> {
+        let mut blob = (key_type.len() as u32).to_be_bytes().to_vec();
+        blob.extend_from_slice(key_type.as_bytes());
+        blob.extend_from_slice(&[0, 0, 0, 1, 42]);
+        blob
+    }
+
+    #[test]
+    fn test_is_certificate_blob() {
+        assert!(is_certificate_blob(&blob("ssh-ed25519-cert-v01@openssh.com")));
+        assert!(is_certificate_blob(&blob("sk-ssh-ed25519-cert-v01@openssh.com")));
+        assert!(!is_certificate_blob(&blob("ssh-ed25519")));
+        assert!(!is_certificate_blob(&[0, 0, 1]));
+        assert!(!is_certificate_blob(&[0, 0, 1, 0, b's']));
+    }
+
+    #[test]
+    fn test_is_security_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("id_ed25519_sk");
+        let key = key.to_str().unwrap();
+        assert!(!is_security_key(key));
+        fs::write(format!("{}.pub", key), "sk-ssh-ed25519@openssh.com AAAA u@host\n").unwrap();
+        assert!(is_security_key(key));
+    }
+
+    #[test]
+    fn test_parse_valid_until() {
+        let output = "id-cert.pub:\n        Type: ssh-ed25519-cert-v01@openssh.com user certificate\n\
+                      \x20       Valid: from 2024-05-02T09:00:00 to 2024-05-02T21:00:00\n";
+        let valid_until = parse_valid_until(output).unwrap().unwrap();
+        assert_eq!(valid_until.format("%Y-%m-%d %H:%M").to_string(), "2024-05-02 21:00");
+        let valid_until = parse_valid_until("Valid: before 2024-05-03T08:30:00").unwrap();
+        assert!(valid_until.is_some());
+        assert_eq!(parse_valid_until("  Valid: forever\n").unwrap(), None);
+        assert!(parse_valid_until("not a certificate").is_err());
+    }
+
+    #[test]
+    fn test_format_expiry_warning() {
+        let now = Local::now();
+        let path = "~/.ssh/id-cert.pub";
+        assert_eq!(format_expiry_warning(path, now + Duration::hours(8), now), None);
+        let warning = format_expiry_warning(path, now + Duration::minutes(30), now).unwrap();
+        assert!(warning.contains("(in 30 min)"));
+        let warning = format_expiry_warning(path, now - Duration::minutes(1), now).unwrap();
+        assert!(warning.contains("expired"));
+    }
+}
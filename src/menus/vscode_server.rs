@@ -0,0 +1,243 @@
+use std::{fs::File, io, path::Path, process::Command};
+use ssh2::Session;
+use chrono::Local;
+use color_eyre::eyre::{bail, eyre, Result};
+use crate::menus::cluster::Cluster;
+use crate::menus::spawner::shell_quote;
+use crate::progress::Progress;
+
+const UPDATE_URL: &str = "https://update.code.visualstudio.com";
+
+// =======================================================================
+//            ARTIFACTS
+// =======================================================================
+
+/// A part of the VS Code remote installation that has to exist in the
+/// remote `~/.vscode-server` directory.
+#[derive(Debug, PartialEq)]
+pub struct Artifact {
+    /// Download url of the tarball.
+    pub url: String,
+    /// Target directory relative to `~/.vscode-server`.
+    pub target: String,
+    /// File inside the target directory that proves the installation.
+    pub check_file: String,
+    /// Name of the extracted file if it has to be renamed to `check_file`.
+    pub extracted_file: Option<String>,
+}
+
+/// Get the artifacts for the given commit and remote architecture:
+/// the remote server and the VS Code cli that launches it.
+pub fn get_artifacts(commit: &str, arch: &str) -> Vec<Artifact> {
+    vec![
+        Artifact {
+            url: format!("{}/commit:{}/server-linux-{}/stable", UPDATE_URL, commit, arch),
+            target: format!("cli/servers/Stable-{}/server", commit),
+            check_file: "bin/code-server".to_string(),
+            extracted_file: None,
+        },
+        Artifact {
+            url: format!("{}/commit:{}/cli-alpine-{}/stable", UPDATE_URL, commit, arch),
+            target: ".".to_string(),
+            // the cli is named `code` in the tarball
+            check_file: format!("code-{}", commit),
+            extracted_file: Some("code".to_string()),
+        },
+    ]
+}
+
+// =======================================================================
+//            LOCAL EDITOR
+// =======================================================================
+
+/// Parse the commit hash from the output of `code --version`.
+/// The second line of the output contains the commit.
+pub fn parse_commit(version_output: &str) -> Option<String> {
+    version_output.lines().nth(1)
+        .map(|line| line.trim().to_string())
+        .filter(|commit| !commit.is_empty())
+}
+
+/// Get the commit hash of the local VS Code installation.
+pub fn get_local_commit() -> Result<String> {
+    let output = Command::new("code").arg("--version").output()?;
+    parse_commit(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| eyre!("Could not detect the commit of the local VS Code"))
+}
+
+/// Map the output of `uname -m` to the architecture name of the
+/// VS Code downloads.
+pub fn parse_arch(uname_output: &str) -> Result<&'static str> {
+    match uname_output.trim() {
+        "x86_64" | "amd64" => Ok("x64"),
+        "aarch64" | "arm64" => Ok("arm64"),
+        "armv7l" | "armv8l" => Ok("armhf"),
+        other => bail!("Unsupported remote architecture: {}", other),
+    }
+}
+
+// =======================================================================
+//            REMOTE OPERATIONS
+// =======================================================================
+
+/// Make sure the VS Code server that matches the local editor exists on
+/// the cluster. Missing artifacts are downloaded on the login node, or
+/// downloaded locally and uploaded via sftp if the login node has no
+/// internet access either. The architecture is the one of the compute
+/// node, which may differ from the login node.
+pub fn preseed(session: &Session, cluster: &Cluster, node_name: &str,
+               progress: &Progress) -> Result<()> {
+    let commit = get_local_commit()?;
+    let (exit_status, uname) = cluster.execute_and_capture(session, &get_arch_command(node_name))?;
+    if exit_status != 0 {
+        bail!("Could not detect the architecture of the node {}", node_name);
+    }
+    let arch = parse_arch(&uname)?;
+    let (_, home) = cluster.execute_and_capture(session, "echo $HOME")?;
+    let server_dir = format!("{}/.vscode-server", home.trim());
+
+    for artifact in get_artifacts(&commit, arch) {
+        let target = format!("{}/{}", server_dir, artifact.target);
+        let command = format!("test -e {}", shell_quote(&format!("{}/{}", target, artifact.check_file)));
+        let (exit_status, _) = cluster.execute_and_capture(session, &command)?;
+        if exit_status == 0 {
+            continue;
+        }
+        progress.check()?;
+        progress.output(&format!("Installing VS Code server component {} ...\n", artifact.url));
+        if !remote_download(session, cluster, &artifact, &target)? {
+            upload(session, cluster, &artifact, &server_dir, &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Get the command that prints the architecture of the compute node by
+/// connecting to it from the login node.
+pub fn get_arch_command(node_name: &str) -> String {
+    format!("ssh -o BatchMode=yes -o ConnectTimeout=30 {} uname -m", node_name)
+}
+
+/// Try to download and extract the artifact on the login node.
+/// Returns false if the login node can not download it.
+fn remote_download(session: &Session, cluster: &Cluster,
+                   artifact: &Artifact, target: &str) -> Result<bool> {
+    let url = shell_quote(&artifact.url);
+    let source = format!("(curl -fsSL {url} || wget -qO- {url})", url = url);
+    let command = extract_command(artifact, target, &source);
+    let (exit_status, _) = cluster.execute_and_capture(session, &command)?;
+    Ok(exit_status == 0)
+}
+
+/// Download the artifact locally, upload it via sftp and extract it
+/// on the login node.
+fn upload(session: &Session, cluster: &Cluster, artifact: &Artifact,
+          server_dir: &str, target: &str) -> Result<()> {
+    // unique names, such that several instances can install at once
+    let file_name = format!("code-remote-{}-{}.tar.gz", std::process::id(),
+                            Local::now().format("%Y%m%d%H%M%S%f"));
+    let local_file = std::env::temp_dir().join(&file_name);
+    // capture the output, such that errors do not end up in the terminal
+    let output = Command::new("curl")
+        .args(["-fsSL", "-o"]).arg(&local_file).arg(&artifact.url)
+        .output()?;
+    if !output.status.success() {
+        bail!("Failed to download {}: {}", artifact.url,
+              String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    let remote_file = format!("{}/{}", server_dir, file_name);
+    cluster.execute_and_capture(session, &format!("mkdir -p {}", shell_quote(server_dir)))?;
+    let sftp = session.sftp()?;
+    let mut remote = sftp.create(Path::new(&remote_file))?;
+    io::copy(&mut File::open(&local_file)?, &mut remote)?;
+    drop(remote);
+    let _ = std::fs::remove_file(&local_file);
+
+    let file = shell_quote(&remote_file);
+    let command = format!(
+        "{}; status=$?; rm -f {file}; exit $status",
+        extract_command(artifact, target, &format!("cat {}", file)), file = file);
+    let (exit_status, _) = cluster.execute_and_capture(session, &command)?;
+    if exit_status != 0 {
+        bail!("Failed to extract the VS Code server on the cluster");
+    }
+    Ok(())
+}
+
+/// Get the command that extracts the tarball printed by the `source`
+/// command into the target directory.
+fn extract_command(artifact: &Artifact, target: &str, source: &str) -> String {
+    let target = shell_quote(target);
+    // the server tarball contains a top level directory, the cli does not
+    let strip = if artifact.target == "." { "" } else { " --strip-components 1" };
+    let mut command = format!("mkdir -p {target} && {source} | tar -xz -C {target}{strip}",
+                              target = target, source = source, strip = strip);
+    if let Some(extracted_file) = &artifact.extracted_file {
+        command.push_str(&format!(
+            " && mv -f {}/{} {}/{}", target, shell_quote(extracted_file),
+            target, shell_quote(&artifact.check_file)));
+    }
+    command
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commit() {
+        let output = "1.92.2\nfee1edb8d6d72a0ddff41e5f71a671c23ed924b9\nx64\n";
+        assert_eq!(parse_commit(output).unwrap(),
+                   "fee1edb8d6d72a0ddff41e5f71a671c23ed924b9");
+        assert_eq!(parse_commit("1.92.2\n"), None);
+    }
+
+    #[test]
+    fn test_parse_arch() {
+        assert_eq!(parse_arch("x86_64\n").unwrap(), "x64");
+        assert_eq!(parse_arch("aarch64").unwrap(), "arm64");
+        assert!(parse_arch("ppc64le").is_err());
+    }
+
+    #[test]
+    fn test_get_artifacts() {
+        let artifacts = get_artifacts("abc", "x64");
+        assert_eq!(artifacts[0].url,
+                   "https://update.code.visualstudio.com/commit:abc/server-linux-x64/stable");
+        assert_eq!(artifacts[0].target, "cli/servers/Stable-abc/server");
+        assert_eq!(artifacts[1].check_file, "code-abc");
+    }
+
+    #[test]
+    fn test_get_arch_command() {
+        assert_eq!(get_arch_command("node1"),
+                   "ssh -o BatchMode=yes -o ConnectTimeout=30 node1 uname -m");
+    }
+
+    #[test]
+    fn test_extract_cli() {
+        // a tarball like the cli download, with the binary `code`
+        let dir = tempfile::tempdir().unwrap();
+        let content = dir.path().join("content");
+        std::fs::create_dir(&content).unwrap();
+        std::fs::write(content.join("code"), "#!/bin/sh\n").unwrap();
+        let tarball = dir.path().join("cli.tar.gz");
+        let status = Command::new("tar").arg("-czf").arg(&tarball)
+            .arg("-C").arg(&content).arg("code").status().unwrap();
+        assert!(status.success());
+
+        let artifact = get_artifacts("abc", "x64").remove(1);
+        let target = dir.path().join("my server").to_str().unwrap().to_string();
+        let source = format!("cat {}", shell_quote(tarball.to_str().unwrap()));
+        let status = Command::new("sh").arg("-c")
+            .arg(extract_command(&artifact, &target, &source)).status().unwrap();
+        assert!(status.success());
+        assert!(Path::new(&target).join(&artifact.check_file).exists());
+        assert!(!Path::new(&target).join("code").exists());
+    }
+}
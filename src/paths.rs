@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+/// Expand a leading `~` to the home directory and `$VAR` or `${VAR}` to
+/// the value of the variable. Unknown variables are kept as they are,
+/// such that the path still shows what is missing.
+pub fn expand_path(path: &str, home: &str,
+                   lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut expanded = String::new();
+    let mut rest = path;
+    if let Some(tail) = path.strip_prefix('~') {
+        if !home.is_empty() && (tail.is_empty() || tail.starts_with('/')) {
+            expanded.push_str(home);
+            rest = tail;
+        }
+    }
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, reference, tail) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &rest[start..start + end + 3], &braced[end + 1..]),
+                None => ("", &rest[start..], ""),
+            },
+            None => {
+                let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], &rest[start..start + end + 1], &after[end..])
+            },
+        };
+        match lookup(name).filter(|_| !name.is_empty()) {
+            Some(value) => expanded.push_str(&value),
+            None => expanded.push_str(reference),
+        }
+        rest = tail;
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Expand a path with the home directory and the environment of this
+/// machine.
+pub fn expand_local(path: &str) -> String {
+    let home = std::env::var("HOME").unwrap_or_default();
+    expand_path(path, &home, |name| std::env::var(name).ok())
+}
+
+/// Expand a path with the environment of a remote machine, as printed
+/// by `env`.
+pub fn expand_remote(path: &str, environment: &HashMap<String, String>) -> String {
+    let home = environment.get("HOME").map(|h| h.as_str()).unwrap_or_default();
+    expand_path(path, home, |name| environment.get(name).cloned())
+}
+
+/// Parse the output of `env` into the variables. Lines without `=`,
+/// e.g. continued values of multi-line variables, are skipped.
+pub fn parse_environment(output: &str) -> HashMap<String, String> {
+    output.lines()
+        .filter_map(|line| line.split_once('='))
+        .filter(|(name, _)| !name.is_empty())
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_path() {
+        let lookup = |name: &str| match name {
+            "WORK" => Some("/work/ab1234".to_string()),
+            "USER" => Some("u".to_string()),
+            _ => None,
+        };
+        let expand = |path: &str| expand_path(path, "/home/u", lookup);
+        assert_eq!(expand("~/.ssh/id_ed25519"), "/home/u/.ssh/id_ed25519");
+        assert_eq!(expand("~"), "/home/u");
+        assert_eq!(expand("~other/key"), "~other/key");
+        assert_eq!(expand("$WORK/project"), "/work/ab1234/project");
+        assert_eq!(expand("/scratch/${USER}_tmp"), "/scratch/u_tmp");
+        assert_eq!(expand("$UNKNOWN/a/${MISSING}"), "$UNKNOWN/a/${MISSING}");
+        assert_eq!(expand("cost$ and ${open"), "cost$ and ${open");
+        assert_eq!(expand_path("~/a", "", lookup), "~/a");
+    }
+
+    #[test]
+    fn test_parse_environment() {
+        let environment = parse_environment("HOME=/home/u\nOPTS=a=b\ncontinued\n");
+        assert_eq!(environment.len(), 2);
+        assert_eq!(environment["OPTS"], "a=b");
+        assert_eq!(expand_remote("~/project", &environment), "/home/u/project");
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc},
+    thread,
+};
+
+use color_eyre::eyre::{eyre, Result};
+use ssh2::Session;
+
+use crate::event::Event;
+use crate::menus::{cluster::SessionType, spawner::SpawnResult};
+
+/// Name of the worker threads of background tasks.
+pub const TASK_THREAD: &str = "code-remote-task";
+
+/// Stages of a connection or a spawn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Connecting,
+    Authenticating,
+    PreparingJob,
+    WaitingForAllocation,
+    PreparingNode,
+    LaunchingEditor,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stage::Connecting => write!(f, "connecting"),
+            Stage::Authenticating => write!(f, "authenticating"),
+            Stage::PreparingJob => write!(f, "preparing the job"),
+            Stage::WaitingForAllocation => write!(f, "waiting for allocation"),
+            Stage::PreparingNode => write!(f, "preparing the node"),
+            Stage::LaunchingEditor => write!(f, "launching editor"),
+        }
+    }
+}
+
+/// Error of a task that was aborted by the user.
+#[derive(Debug)]
+pub struct Aborted;
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Aborted")
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+/// Messages of a task on a worker thread to the user interface.
+pub enum TaskEvent {
+    /// The task entered the next stage.
+    Stage(Stage),
+    /// Output of a remote command.
+    Output(String),
+    /// The connection task finished.
+    Connected(SessionType, Result<Session>),
+    /// The spawn task finished.
+    Spawned(Result<SpawnResult>),
+}
+
+impl fmt::Debug for TaskEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TaskEvent::Stage(stage) => write!(f, "Stage({:?})", stage),
+            TaskEvent::Output(text) => write!(f, "Output({:?})", text),
+            TaskEvent::Connected(session_type, result) => write!(
+                f, "Connected({:?}, {})", session_type,
+                if result.is_ok() { "Ok" } else { "Err" }),
+            TaskEvent::Spawned(result) => write!(f, "Spawned({:?})", result),
+        }
+    }
+}
+
+/// Progress of a running task. It reports the stages and the output of
+/// remote commands, and tells the task when the user aborted it.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    id: u64,
+    sender: Option<mpsc::Sender<Event>>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl Progress {
+    /// Progress of a task in the terminal user interface. The events are
+    /// sent through the channel of the event handler.
+    pub fn new(id: u64, sender: Option<mpsc::Sender<Event>>) -> Progress {
+        Progress { id, sender, aborted: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// Progress of a command line task: the output of remote commands is
+    /// written to stderr and the task is never aborted.
+    pub fn stderr() -> Progress {
+        Progress::new(0, None)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Relaxed)
+    }
+
+    /// Fail with [`Aborted`] if the user aborted the task.
+    pub fn check(&self) -> Result<()> {
+        if self.is_aborted() {
+            return Err(eyre!(Aborted));
+        }
+        Ok(())
+    }
+
+    /// Enter the next stage. Fails if the task was aborted, such that
+    /// every stage is a point where the task stops.
+    pub fn stage(&self, stage: Stage) -> Result<()> {
+        self.check()?;
+        self.send(TaskEvent::Stage(stage));
+        Ok(())
+    }
+
+    /// Report output of a remote command.
+    pub fn output(&self, text: &str) {
+        match self.sender {
+            Some(_) => self.send(TaskEvent::Output(text.to_string())),
+            None => eprint!("{}", text),
+        }
+    }
+
+    /// Report the result of the task.
+    pub fn finish(&self, event: TaskEvent) {
+        self.send(event);
+    }
+
+    fn send(&self, event: TaskEvent) {
+        if let Some(sender) = &self.sender {
+            // the user interface may be gone already
+            let _ = sender.send(Event::Task(self.id, event));
+        }
+    }
+}
+
+/// Run a task on a worker thread and send its result as the event that
+/// `finish` creates. A panic of the task is reported as its error.
+pub fn spawn_task<T: 'static>(
+    progress: Progress,
+    task: impl FnOnce(&Progress) -> Result<T> + Send + 'static,
+    finish: impl FnOnce(Result<T>) -> TaskEvent + Send + 'static) -> Result<()> {
+    thread::Builder::new().name(TASK_THREAD.to_string()).spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| task(&progress)))
+            .unwrap_or_else(|payload| {
+                let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                Err(eyre!("Unexpected failure: {}", message))
+            });
+        progress.finish(finish(result));
+    })?;
+    Ok(())
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_events() {
+        let (sender, receiver) = mpsc::channel();
+        let progress = Progress::new(7, Some(sender));
+        progress.stage(Stage::Connecting).unwrap();
+        progress.output("salloc: Pending job allocation 42\n");
+        match receiver.try_recv().unwrap() {
+            Event::Task(7, TaskEvent::Stage(Stage::Connecting)) => {},
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(matches!(receiver.try_recv().unwrap(),
+                         Event::Task(7, TaskEvent::Output(_))));
+    }
+
+    #[test]
+    fn test_spawn_task_panic() {
+        let (sender, receiver) = mpsc::channel();
+        let progress = Progress::new(1, Some(sender));
+        spawn_task(progress, |_| -> Result<SpawnResult> { panic!("broken") },
+                   TaskEvent::Spawned).unwrap();
+        match receiver.recv().unwrap() {
+            Event::Task(1, TaskEvent::Spawned(Err(e))) => {
+                assert_eq!(e.to_string(), "Unexpected failure: broken");
+            },
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_abort() {
+        let progress = Progress::stderr();
+        let worker = progress.clone();
+        assert!(worker.check().is_ok());
+        progress.abort();
+        let error = worker.stage(Stage::WaitingForAllocation).unwrap_err();
+        assert!(error.downcast_ref::<Aborted>().is_some());
+    }
+}
@@ -0,0 +1,166 @@
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local};
+use color_eyre::eyre::Result;
+
+use crate::config::state_dir;
+
+/// Name of the file with the log of the last run.
+pub const LOG_FILE: &str = "last-run.log";
+
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    pub time: DateTime<Local>,
+    pub text: String,
+}
+
+/// Timestamped log of the last connection or spawn, with the output of
+/// the remote commands.
+#[derive(Debug, Default)]
+pub struct RunLog {
+    lines: Vec<LogLine>,
+    /// Output after the last line break, added once the line is complete.
+    partial: String,
+    /// Number of lines scrolled up from the bottom.
+    scroll: usize,
+}
+
+impl RunLog {
+    /// Start the log of a new run.
+    pub fn start(&mut self, title: &str) {
+        *self = RunLog::default();
+        self.push_line(title);
+    }
+
+    /// Add a complete line.
+    pub fn push_line(&mut self, text: &str) {
+        self.flush();
+        self.lines.push(LogLine { time: Local::now(), text: text.to_string() });
+    }
+
+    /// Add output of a remote command. The output may end in the middle
+    /// of a line; the rest of the line follows with the next output.
+    pub fn push_output(&mut self, text: &str) {
+        for c in text.chars() {
+            match c {
+                '\n' | '\r' => self.flush(),
+                c => self.partial.push(c),
+            }
+        }
+    }
+
+    /// Add the incomplete line of output, if any.
+    fn flush(&mut self) {
+        if !self.partial.is_empty() {
+            let text = std::mem::take(&mut self.partial);
+            self.lines.push(LogLine { time: Local::now(), text });
+        }
+    }
+
+    pub fn lines(&self) -> &[LogLine] {
+        &self.lines
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty() && self.partial.is_empty()
+    }
+
+    /// The last non-empty line, including an incomplete one.
+    pub fn last_line(&self) -> Option<&str> {
+        std::iter::once(self.partial.as_str())
+            .chain(self.lines.iter().rev().map(|l| l.text.as_str()))
+            .map(|text| text.trim())
+            .find(|text| !text.is_empty())
+    }
+
+    // =======================================================================
+    //             SCROLLING
+    // =======================================================================
+
+    pub fn scroll(&self) -> usize {
+        self.scroll
+    }
+
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.scroll = (self.scroll + lines).min(self.lines.len().saturating_sub(1));
+    }
+
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.scroll = self.scroll.saturating_sub(lines);
+    }
+
+    /// The lines for the log pane, with the time of day.
+    pub fn format_lines(&self) -> Vec<String> {
+        self.lines.iter()
+            .map(|line| format!("{} {}", line.time.format("%H:%M:%S"), line.text))
+            .collect()
+    }
+
+    // =======================================================================
+    //             FILE OPERATIONS
+    // =======================================================================
+
+    /// Write the log to the state directory and return the path.
+    pub fn save(&mut self) -> Result<PathBuf> {
+        self.save_to(&state_dir()?)
+    }
+
+    pub fn save_to(&mut self, dir: &Path) -> Result<PathBuf> {
+        self.flush();
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(LOG_FILE);
+        let content: String = self.lines.iter()
+            .map(|line| format!("{} {}\n", line.time.format("%Y-%m-%d %H:%M:%S"), line.text))
+            .collect();
+        std::fs::write(&path, content)?;
+        Ok(path)
+    }
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_output() {
+        let mut log = RunLog::default();
+        log.start("Spawning gpu");
+        log.push_output("salloc: Pending job allocation 42\nsalloc: job 42 ");
+        assert_eq!(log.lines().len(), 2);
+        assert_eq!(log.last_line(), Some("salloc: job 42"));
+        log.push_output("queued and waiting for resources\n\n");
+        assert_eq!(log.lines().len(), 3);
+        assert_eq!(log.lines()[2].text, "salloc: job 42 queued and waiting for resources");
+    }
+
+    #[test]
+    fn test_scroll() {
+        let mut log = RunLog::default();
+        for i in 0..5 {
+            log.push_line(&i.to_string());
+        }
+        log.scroll_up(10);
+        assert_eq!(log.scroll(), 4);
+        log.scroll_down(3);
+        assert_eq!(log.scroll(), 1);
+        log.scroll_down(3);
+        assert_eq!(log.scroll(), 0);
+    }
+
+    #[test]
+    fn test_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = RunLog::default();
+        log.start("Connecting to levante");
+        log.push_output("incomplete");
+        let path = log.save_to(dir.path()).unwrap();
+        let content = std::fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" Connecting to levante"));
+        assert!(lines[1].ends_with(" incomplete"));
+    }
+}
@@ -1,7 +1,8 @@
-use std::{io, panic};
+use std::{io, panic, thread};
 
 use color_eyre::Result;
 use crossterm::{
+    cursor,
     event::{DisableMouseCapture, EnableMouseCapture},
     terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
 };
@@ -9,7 +10,7 @@ use crossterm::{
 pub type CrosstermTerminal =
     ratatui::Terminal<ratatui::backend::CrosstermBackend<std::io::Stderr>>;
 
-use crate::{app::App, event::EventHandler, ui};
+use crate::{app::App, event::EventHandler, progress::TASK_THREAD, ui};
 
 /// Representation of a terminal user interface.
 ///
@@ -48,7 +49,13 @@ impl Tui {
         // This way, you won't have your terminal messed up if an unexpected error happens.
         let panic_hook = panic::take_hook();
         panic::set_hook(Box::new(move |panic| {
-            Self::reset().expect("failed to reset the terminal");
+            // panics of background tasks are caught and shown in the
+            // error popup, the terminal user interface keeps running
+            if thread::current().name() == Some(TASK_THREAD) {
+                return;
+            }
+            let _ = Self::reset();
+            let _ = crossterm::execute!(io::stderr(), cursor::Show);
             panic_hook(panic);
         }));
 
@@ -5,7 +5,8 @@ use ratatui::{
 };
 use crate::app::{App, Menu, Popup};
 use crate::double_column_menu::double_column_menu::DoubleColumnMenu;
-use crate::double_column_menu::render_helper_functions::render_info_dialog;
+use crate::double_column_menu::render_helper_functions::{
+    render_info_dialog, render_log_dialog, render_select_dialog};
 
 pub fn render(app: &mut App, f: &mut Frame) {
 
@@ -22,7 +23,7 @@ pub fn render(app: &mut App, f: &mut Frame) {
 
     // make a info text at the bottom
     f.render_widget(
-        Paragraph::new("Press Ctrl-C` or `q` to stop running.")
+        Paragraph::new("Press Ctrl-C` or `q` to stop running, `o` to show the log of the last run.")
             .style(Style::default().fg(Color::LightCyan))
             .alignment(Alignment::Center),
         outer_layout[1],
@@ -38,10 +39,49 @@ pub fn render(app: &mut App, f: &mut Frame) {
     }
 
     match &app.popup {
+        // several messages may be shown at once
         Popup::Error(error_text) => {
-            render_info_dialog(f, &error_text, Color::Red, 2);
+            let lines = error_text.lines().count() as u16;
+            render_info_dialog(f, error_text, Color::Red, lines.max(2));
         }
-        _ => {}
+        Popup::Info(info_text) => {
+            let lines = info_text.lines().count() as u16;
+            render_info_dialog(f, info_text, Color::Yellow, lines.max(3));
+        }
+        Popup::JobSelect(jobs, counter) => {
+            let items = jobs.iter().map(|job| job.format_line()).collect();
+            render_select_dialog(
+                f, "Attach to job (`Enter` to attach, `Esc` to cancel): ",
+                items, counter.get_value() as usize);
+        }
+        Popup::Log => {
+            render_log_dialog(
+                f, " Log of the last run ", " `↑/↓` to scroll, `Esc` to close ",
+                app.log.format_lines(), app.log.scroll(), Color::Blue);
+        }
+        Popup::Secret(input) => {
+            let color = match input.error {
+                Some(_) => Color::Red,
+                None => Color::Yellow,
+            };
+            render_info_dialog(f, &input.format_dialog(), color, 3);
+        }
+        Popup::Path(input) => {
+            let text = input.format_dialog();
+            render_info_dialog(f, &text, Color::Yellow, text.lines().count() as u16);
+        }
+        Popup::ImportConflict(pending) => {
+            let name = pending.conflicts.first().cloned().unwrap_or_default();
+            let new_name = app.cluster_menu.new_entry_name(&name);
+            render_info_dialog(f, &pending.format_dialog(&new_name), Color::Yellow, 3);
+        }
+        Popup::None => {}
+    }
+
+    if let Some(task) = &app.task {
+        render_log_dialog(
+            f, &task.format_status(), " `↑/↓` to scroll, `Esc` to abort ",
+            app.log.format_lines(), app.log.scroll(), Color::Yellow);
     }
 
 }
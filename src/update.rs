@@ -1,17 +1,89 @@
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 
-use crate::app::{App, Menu, Popup};
+use crate::app::{Action, App, Menu, PathPurpose, Popup};
 use crate::double_column_menu::double_column_menu::DoubleColumnMenu;
+use crate::menus::bundle::Conflict;
+
+/// Number of lines that page up and down scroll in the log.
+const LOG_PAGE: usize = 10;
 
 pub fn update(app: &mut App, key_event: KeyEvent) {
+    // a running task only listens for the abort and scrolls its log
+    if app.task.is_some() {
+        match key_event.code {
+            KeyCode::Esc => app.abort_task(),
+            KeyCode::Char('c') | KeyCode::Char('C')
+                if key_event.modifiers == KeyModifiers::CONTROL => app.quit(),
+            _ => scroll_log(app, key_event),
+        };
+        return;
+    }
+    if let Popup::Secret(input) = &mut app.popup {
+        match key_event.code {
+            KeyCode::Char('c') | KeyCode::Char('C')
+                if key_event.modifiers == KeyModifiers::CONTROL => app.quit(),
+            KeyCode::Char(c) => input.value.push(c),
+            KeyCode::Backspace => { input.value.pop(); },
+            KeyCode::Enter => app.action = Action::SubmitSecret,
+            KeyCode::Esc => app.popup = Popup::None,
+            _ => {}
+        };
+        app.handle_action();
+        return;
+    }
+    if let Popup::Path(input) = &mut app.popup {
+        match key_event.code {
+            KeyCode::Char('c') | KeyCode::Char('C')
+                if key_event.modifiers == KeyModifiers::CONTROL => app.quit(),
+            KeyCode::Char(c) => { input.value.push(c); input.overwrite = false; },
+            KeyCode::Backspace => { input.value.pop(); input.overwrite = false; },
+            KeyCode::Tab if input.purpose == PathPurpose::Export => {
+                input.strip_user = !input.strip_user;
+            },
+            KeyCode::Enter => app.action = Action::SubmitPath,
+            KeyCode::Esc => app.popup = Popup::None,
+            _ => {}
+        };
+        app.handle_action();
+        return;
+    }
+    if let Popup::ImportConflict(_) = app.popup {
+        match key_event.code {
+            KeyCode::Char('r') => app.action = Action::AnswerConflict(Conflict::Rename),
+            KeyCode::Char('m') => app.action = Action::AnswerConflict(Conflict::Merge),
+            KeyCode::Char('s') => app.action = Action::AnswerConflict(Conflict::Skip),
+            KeyCode::Esc | KeyCode::Char('q') => app.popup = Popup::None,
+            _ => {}
+        };
+        app.handle_action();
+        return;
+    }
+    if let Popup::Log = app.popup {
+        match key_event.code {
+            KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('o') => app.popup = Popup::None,
+            _ => scroll_log(app, key_event),
+        };
+        return;
+    }
+    // selection popups take all the input
+    if let Popup::JobSelect(_, counter) = &mut app.popup {
+        match key_event.code {
+            KeyCode::Down | KeyCode::Char('j') => counter.increment(),
+            KeyCode::Up | KeyCode::Char('k') => counter.decrement(),
+            KeyCode::Enter => app.action = Action::AttachJob,
+            KeyCode::Esc | KeyCode::Char('q') => app.popup = Popup::None,
+            _ => {}
+        };
+        app.handle_action();
+        return;
+    }
     // any input should reset the error popup
     app.popup = Popup::None;
     // Ctrl + C should always quit, regardless of the input mode
     match key_event.code {
-        KeyCode::Char('c') | KeyCode::Char('C') => {
-            if key_event.modifiers == KeyModifiers::CONTROL {
-                app.quit()
-            }
+        KeyCode::Char('c') | KeyCode::Char('C')
+            if key_event.modifiers == KeyModifiers::CONTROL => {
+            app.quit()
         }
         _ => {}
     };
@@ -21,3 +93,133 @@ pub fn update(app: &mut App, key_event: KeyEvent) {
     };
     app.handle_action();
 }
+
+fn scroll_log(app: &mut App, key_event: KeyEvent) {
+    match key_event.code {
+        KeyCode::Up | KeyCode::Char('k') => app.log.scroll_up(1),
+        KeyCode::Down | KeyCode::Char('j') => app.log.scroll_down(1),
+        KeyCode::PageUp => app.log.scroll_up(LOG_PAGE),
+        KeyCode::PageDown => app.log.scroll_down(LOG_PAGE),
+        _ => {}
+    };
+}
+
+// =======================================================================
+//           TESTS
+// =======================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::app::{PathInput, PendingImport, SecretInput};
+    use crate::menus::bundle::Bundle;
+    use crate::menus::cluster::SessionType;
+
+    fn press(app: &mut App, code: KeyCode) {
+        update(app, KeyEvent::new(code, KeyModifiers::NONE));
+    }
+
+    #[test]
+    fn test_secret_input() {
+        let mut app = App::default();
+        app.open_session(SessionType::Password).unwrap();
+        for c in "secret".chars() {
+            press(&mut app, KeyCode::Char(c));
+        }
+        press(&mut app, KeyCode::Backspace);
+        match &app.popup {
+            Popup::Secret(input) => {
+                assert_eq!(input.value, "secre");
+                assert!(input.format_dialog().contains("\n*****\n"));
+            },
+            popup => panic!("unexpected popup {:?}", popup),
+        }
+        // keys of the menu go to the input
+        press(&mut app, KeyCode::Char('q'));
+        assert!(!app.should_quit);
+        press(&mut app, KeyCode::Esc);
+        assert!(matches!(app.popup, Popup::None));
+    }
+
+    #[test]
+    fn test_import_conflicts() {
+        let mut app = App::default();
+        let bundle = Bundle { cluster_version: 0, preset_version: 0, cluster: Vec::new() };
+        app.popup = Popup::ImportConflict(PendingImport {
+            bundle,
+            conflicts: vec!["levante".to_string(), "mistral".to_string()],
+            answers: HashMap::new(),
+        });
+        press(&mut app, KeyCode::Char('x'));
+        press(&mut app, KeyCode::Char('m'));
+        match &app.popup {
+            Popup::ImportConflict(pending) => {
+                assert_eq!(pending.conflicts, vec!["mistral"]);
+                assert_eq!(pending.answers["levante"], Conflict::Merge);
+                assert!(pending.format_dialog("mistral(1)").contains("add it as mistral(1)"));
+            },
+            popup => panic!("unexpected popup {:?}", popup),
+        }
+        press(&mut app, KeyCode::Esc);
+        assert!(matches!(app.popup, Popup::None));
+    }
+
+    #[test]
+    fn test_path_input() {
+        let mut app = App::default();
+        app.open_import();
+        for c in "~/b.toml".chars() {
+            press(&mut app, KeyCode::Char(c));
+        }
+        match &app.popup {
+            Popup::Path(input) => {
+                assert_eq!(input.purpose, PathPurpose::Import);
+                assert!(input.format_dialog().contains("\n~/b.toml\n"));
+            },
+            popup => panic!("unexpected popup {:?}", popup),
+        }
+        press(&mut app, KeyCode::Esc);
+        assert!(matches!(app.popup, Popup::None));
+    }
+
+    #[test]
+    fn test_export_input() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut app = App::default();
+        app.popup = Popup::Path(PathInput {
+            prompt: "Export levante and its sessions to:".to_string(),
+            value: file.path().display().to_string(),
+            purpose: PathPurpose::Export,
+            strip_user: false,
+            overwrite: false,
+        });
+        press(&mut app, KeyCode::Tab);
+        // the existing file is not replaced without confirmation
+        press(&mut app, KeyCode::Enter);
+        match &app.popup {
+            Popup::Path(input) => {
+                assert!(input.strip_user);
+                assert!(input.overwrite);
+                assert!(input.format_dialog().contains("removed (`Tab` to keep)"));
+                assert!(input.format_dialog().contains("Press `Enter` to overwrite it"));
+            },
+            popup => panic!("unexpected popup {:?}", popup),
+        }
+        // editing the path asks again
+        press(&mut app, KeyCode::Backspace);
+        match &app.popup {
+            Popup::Path(input) => assert!(!input.overwrite),
+            popup => panic!("unexpected popup {:?}", popup),
+        }
+        assert!(std::fs::read_to_string(file.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_secret_input_retry() {
+        let error = Some("Error: Authentication failed. Please try again.".to_string());
+        let input = SecretInput::new(SessionType::Passphrase, error);
+        assert!(input.format_dialog().starts_with("Enter the passphrase"));
+        assert!(input.format_dialog().ends_with("Please try again."));
+    }
+}